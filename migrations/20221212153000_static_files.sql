@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS static_files (
+  name VARCHAR(255) NOT NULL PRIMARY KEY,
+  size BIGINT UNSIGNED NOT NULL,
+  content_type VARCHAR(255) NOT NULL,
+  hash VARCHAR(64) NOT NULL
+)
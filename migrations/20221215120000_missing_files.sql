@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN missing BOOLEAN NOT NULL DEFAULT FALSE
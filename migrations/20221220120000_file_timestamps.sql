@@ -0,0 +1 @@
+ALTER TABLE files ADD COLUMN created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
@@ -0,0 +1 @@
+ALTER TABLE files ADD INDEX files_bucket_hash (bucket, hash), ADD INDEX files_bucket_file_id (bucket, file_id)
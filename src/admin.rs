@@ -0,0 +1,110 @@
+use std::fmt::{self, Debug};
+
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request, State,
+};
+
+/// The token administrative routes are authenticated with, configured with the
+/// `EFFIS_ADMIN_TOKEN` environment variable
+pub struct AdminConf {
+    token: Option<String>,
+}
+
+impl Debug for AdminConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminConf")
+            .field("token", &self.token.as_ref().map(|_| "redacted"))
+            .finish()
+    }
+}
+
+impl AdminConf {
+    pub fn new(token: Option<String>) -> AdminConf {
+        AdminConf {
+            token: token.filter(|t| !t.is_empty()),
+        }
+    }
+}
+
+/// Compares two strings in constant time to avoid leaking the token through timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// A request guard which only succeeds for requests carrying the admin token in their
+/// `Authorization` header
+#[derive(Debug)]
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let conf = try_outcome!(request.guard::<&State<AdminConf>>().await);
+        let token = match &conf.token {
+            Some(token) => token,
+            None => return Outcome::Failure((Status::Forbidden, ())),
+        };
+        match request.headers().get_one("Authorization") {
+            Some(header)
+                if constant_time_eq(
+                    header.strip_prefix("Bearer ").unwrap_or(header).as_bytes(),
+                    token.as_bytes(),
+                ) =>
+            {
+                Outcome::Success(Admin)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+
+    use super::{Admin, AdminConf};
+
+    #[get("/")]
+    fn admin_only(_admin: Admin) {}
+
+    fn client(token: Option<&str>) -> Client {
+        let rocket = rocket::build()
+            .manage(AdminConf::new(token.map(str::to_string)))
+            .mount("/", routes![admin_only]);
+        Client::untracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn requires_the_admin_token() {
+        let client = client(Some("admin-token"));
+        let response = client.get("/").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/")
+            .header(Header::new("Authorization", "Bearer wrong-token"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        let response = client
+            .get("/")
+            .header(Header::new("Authorization", "Bearer admin-token"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn forbids_everything_without_a_token() {
+        let client = client(Some(""));
+        let response = client
+            .get("/")
+            .header(Header::new("Authorization", "Bearer "))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+}
@@ -0,0 +1,110 @@
+use std::convert::Infallible;
+
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::error::EffisError;
+
+/// The SHA-256 checksum a client expects an uploaded file to have, taken from the
+/// `X-Checksum-SHA256` header or, failing that, the `sha-256` entry of the `Content-Digest` header
+///
+/// Both headers describe the uploaded file rather than the multipart body it's sent in
+#[derive(Debug)]
+pub struct ChecksumHeader(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ChecksumHeader {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        let checksum = headers
+            .get_one("X-Checksum-SHA256")
+            .map(|c| c.trim().to_string())
+            .or_else(|| headers.get_one("Content-Digest").and_then(content_digest));
+        Outcome::Success(ChecksumHeader(checksum))
+    }
+}
+
+/// Gets the `sha-256` digest out of a `Content-Digest` header
+fn content_digest(header: &str) -> Option<String> {
+    header.split(',').find_map(|entry| {
+        let (algorithm, value) = entry.split_once('=')?;
+        algorithm
+            .trim()
+            .eq_ignore_ascii_case("sha-256")
+            .then(|| value.trim().trim_matches(':').to_string())
+    })
+}
+
+fn checksum_error(error: &str) -> EffisError {
+    EffisError::Validation {
+        field_name: "checksum".to_string(),
+        error: error.to_string(),
+    }
+}
+
+/// Parses a SHA-256 checksum encoded as either hex or base64 into its hex form
+fn parse(checksum: &str) -> Option<String> {
+    if checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Some(checksum.to_ascii_lowercase());
+    }
+    let bytes = base64::decode(checksum).ok()?;
+    (bytes.len() == 32).then(|| bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Makes sure the hash of an uploaded file matches the checksum its client sent
+pub fn verify(hash: &str, checksum: &str) -> Result<(), EffisError> {
+    let checksum = parse(checksum)
+        .ok_or_else(|| checksum_error("The checksum must be a hex or base64 SHA-256 hash"))?;
+    if checksum != hash {
+        log::info!(
+            "Rejected upload with checksum {} and hash {}",
+            checksum,
+            hash
+        );
+        return Err(checksum_error(
+            "The uploaded file does not match the checksum",
+        ));
+    }
+    Ok(())
+}
+
+/// The value of the `Digest` header of a file with a hex encoded SHA-256 hash
+pub fn digest_header(hash: &str) -> Option<String> {
+    let bytes = (0..hash.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hash.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(format!("sha-256={}", base64::encode(bytes)))
+}
+
+/// The value of the `Digest` header of a body, hashed as it's sent
+pub fn body_digest_header(data: &[u8]) -> String {
+    format!("sha-256={}", base64::encode(Sha256::digest(data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::{body_digest_header, digest_header, verify};
+
+    #[test]
+    fn digests_of_hashes_and_bodies_match() {
+        let hash = format!("{:x}", Sha256::digest(b"effis"));
+        assert_eq!(digest_header(&hash), Some(body_digest_header(b"effis")));
+    }
+
+    #[test]
+    fn hex_and_base64_checksums_are_verified() {
+        let hash = format!("{:x}", Sha256::digest(b"effis"));
+        assert!(verify(&hash, &hash.to_ascii_uppercase()).is_ok());
+        assert!(verify(&hash, &base64::encode(Sha256::digest(b"effis"))).is_ok());
+        assert!(verify(&hash, &base64::encode(Sha256::digest(b"other"))).is_err());
+    }
+}
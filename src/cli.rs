@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+/// The Eludris file server
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run the server, the default when no subcommand is passed
+    Serve,
+    /// Run the database migrations and exit
+    Migrate,
+    /// Move every blob to the path the configured storage layout stores it at
+    MigrateStorage,
+    /// Check that stored blobs and the database are consistent
+    Fsck {
+        /// Remove orphaned blobs and mark the files whose blobs are missing
+        #[arg(long)]
+        repair: bool,
+        /// Skip hashing every blob
+        #[arg(long)]
+        skip_hashes: bool,
+        /// Write the JSON report to a file instead of stdout
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// Print the effective configuration, with secrets redacted
+    Config,
+    /// Import every file under a directory, or listed in a CSV or JSON manifest, into a bucket
+    ///
+    /// Files keep their names and modification times unless the manifest says otherwise, running
+    /// an interrupted import again picks up where it left off
+    Import {
+        /// A directory or a manifest with `path`, `name`, `spoiler` and `created_at` columns
+        source: PathBuf,
+        #[arg(long, default_value = "attachments")]
+        bucket: String,
+        /// Mark the imported files as spoilers unless the manifest says otherwise
+        #[arg(long)]
+        spoiler: bool,
+        /// Where imported files get recorded, `<source>.import.jsonl` by default
+        #[arg(long)]
+        journal: Option<PathBuf>,
+    },
+    /// Export every file of a bucket into a directory along with a manifest
+    Export { bucket: String, dir: PathBuf },
+    /// Remove a file and purge its cached metadata
+    ///
+    /// Running servers keep files in their in-memory caches, pass every one of them with
+    /// `--server` to purge the file from those too, authenticated with `EFFIS_ADMIN_TOKEN`,
+    /// otherwise they may keep serving it until it gets evicted
+    Purge {
+        bucket: String,
+        id: u128,
+        /// The URL of a running server to purge the file from, can be passed several times
+        #[arg(long = "server")]
+        servers: Vec<String>,
+    },
+    /// Check that MySQL and Redis can be reached
+    Check,
+}
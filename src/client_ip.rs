@@ -0,0 +1,441 @@
+use std::{
+    env,
+    fmt::{self, Display},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+/// The networks proxies are trusted from by default, loopback and private networks
+const DEFAULT_TRUSTED_PROXIES: &str =
+    "127.0.0.0/8, 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, ::1/128, fc00::/7";
+/// The headers the client IP is read from by default, in order of preference
+const DEFAULT_HEADERS: &str = "X-Forwarded-For, X-Real-IP";
+/// The default length of the prefix IPv6 clients are grouped by
+const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+/// A network in CIDR notation, `10.0.0.0/8` for example
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, normalize(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                mask_v4(net, self.prefix) == mask_v4(ip, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                mask_v6(net, self.prefix) == mask_v6(ip, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = normalize(
+            addr.parse::<IpAddr>()
+                .map_err(|_| format!("Invalid address \"{}\"", addr))?,
+        );
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid prefix length \"{}\"", prefix))?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+fn mask_v4(ip: Ipv4Addr, prefix: u8) -> u32 {
+    if prefix == 0 {
+        0
+    } else {
+        u32::from(ip) & (u32::MAX << (32 - prefix as u32))
+    }
+}
+
+fn mask_v6(ip: Ipv6Addr, prefix: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        u128::from(ip) & (u128::MAX << (128 - prefix as u32))
+    }
+}
+
+/// Treats IPv4-mapped IPv6 addresses as the IPv4 addresses they are
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// How the address of a client is resolved, configured with the `EFFIS_TRUSTED_PROXIES`,
+/// `EFFIS_CLIENT_IP_HEADERS` and `EFFIS_IPV6_PREFIX` environment variables
+///
+/// The headers are only read when the request comes from a trusted proxy, they're tried in order
+/// and can be any of `X-Forwarded-For`, `Forwarded` or a header with a single address like
+/// `X-Real-IP` or `CF-Connecting-IP`
+#[derive(Debug)]
+pub struct ProxyConf {
+    trusted_proxies: Vec<Cidr>,
+    headers: Vec<String>,
+    ipv6_prefix: u8,
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+impl ProxyConf {
+    pub fn from_env() -> Result<ProxyConf, anyhow::Error> {
+        let trusted_proxies = list(
+            &env::var("EFFIS_TRUSTED_PROXIES")
+                .unwrap_or_else(|_| DEFAULT_TRUSTED_PROXIES.to_string()),
+        )
+        .iter()
+        .map(|cidr| cidr.parse::<Cidr>())
+        .collect::<Result<Vec<Cidr>, String>>()
+        .map_err(anyhow::Error::msg)
+        .context("Invalid \"EFFIS_TRUSTED_PROXIES\" environment variable")?;
+        let headers = list(
+            &env::var("EFFIS_CLIENT_IP_HEADERS").unwrap_or_else(|_| DEFAULT_HEADERS.to_string()),
+        );
+        let ipv6_prefix = match env::var("EFFIS_IPV6_PREFIX") {
+            Ok(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|p| *p <= 128)
+                .ok_or_else(|| anyhow::anyhow!("Expected a prefix length of at most 128"))
+                .context("Invalid \"EFFIS_IPV6_PREFIX\" environment variable")?,
+            Err(_) => DEFAULT_IPV6_PREFIX,
+        };
+        Ok(ProxyConf {
+            trusted_proxies,
+            headers,
+            ipv6_prefix,
+        })
+    }
+
+    /// The client with an address, grouped like the clients of requests are
+    pub fn client(&self, ip: IpAddr) -> ClientIP {
+        ClientIP {
+            ip: normalize(ip),
+            ipv6_prefix: self.ipv6_prefix,
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    /// Gets the client out of a chain of addresses, the rightmost address which isn't a trusted
+    /// proxy since anything before it could've been made up by the client
+    fn chain_client(&self, chain: Vec<IpAddr>) -> Option<IpAddr> {
+        chain
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or_else(|| chain.first())
+            .copied()
+    }
+
+    fn header_client(&self, name: &str, request: &Request<'_>) -> Option<IpAddr> {
+        let values = request.headers().get(name);
+        if name.eq_ignore_ascii_case("X-Forwarded-For") {
+            self.chain_client(
+                values
+                    .flat_map(|v| v.split(','))
+                    .filter_map(|ip| parse_ip(ip.trim()))
+                    .collect(),
+            )
+        } else if name.eq_ignore_ascii_case("Forwarded") {
+            self.chain_client(
+                values
+                    .flat_map(|v| v.split(','))
+                    .flat_map(|element| element.split(';'))
+                    .filter_map(|pair| {
+                        let (key, value) = pair.trim().split_once('=')?;
+                        key.eq_ignore_ascii_case("for")
+                            .then(|| parse_ip(value.trim_matches('"')))?
+                    })
+                    .collect(),
+            )
+        } else {
+            values.last().and_then(|ip| parse_ip(ip.trim()))
+        }
+    }
+
+    /// Resolves the address of the client which sent a request
+    fn resolve(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let peer = normalize(request.remote()?.ip());
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+        self.headers
+            .iter()
+            .find_map(|name| self.header_client(name, request))
+            .or(Some(peer))
+    }
+}
+
+/// Parses an address which might have a port, or be bracketed like in `Forwarded` headers
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(normalize(ip));
+    }
+    let host = match value.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => value.rsplit_once(':').map(|(host, _)| host)?,
+    };
+    host.parse::<IpAddr>().ok().map(normalize)
+}
+
+/// The resolved address of a request, cached so it's only resolved once
+struct ResolvedIP(Option<IpAddr>);
+
+/// The address of a client, resolved through the trusted proxies
+///
+/// It displays as the network ratelimits are keyed on, which groups IPv6 clients by the
+/// configured prefix so one client can't evade them by rotating through its own addresses
+#[derive(Debug, Clone, Copy)]
+pub struct ClientIP {
+    ip: IpAddr,
+    ipv6_prefix: u8,
+}
+
+impl ClientIP {
+    /// The exact address of the client
+    pub fn ip(&self) -> IpAddr {
+        self.ip
+    }
+}
+
+impl Display for ClientIP {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.ip {
+            IpAddr::V6(ip) if self.ipv6_prefix < 128 => write!(
+                f,
+                "{}/{}",
+                Ipv6Addr::from(mask_v6(ip, self.ipv6_prefix)),
+                self.ipv6_prefix
+            ),
+            ip => write!(f, "{}", ip),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ClientIP {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let conf = match request.rocket().state::<ProxyConf>() {
+            Some(conf) => conf,
+            None => {
+                log::error!("The proxy configuration isn't managed");
+                return Outcome::Failure((Status::InternalServerError, ()));
+            }
+        };
+        match request.local_cache(|| ResolvedIP(conf.resolve(request))).0 {
+            Some(ip) => Outcome::Success(conf.client(ip)),
+            None => Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{http::Header, local::blocking::Client};
+
+    use super::*;
+
+    fn conf() -> ProxyConf {
+        ProxyConf {
+            trusted_proxies: list(DEFAULT_TRUSTED_PROXIES)
+                .iter()
+                .map(|cidr| cidr.parse().unwrap())
+                .collect(),
+            headers: list("X-Forwarded-For, Forwarded, X-Real-IP"),
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+        }
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        let cidr = "10.0.0.0/8".parse::<Cidr>().unwrap();
+        assert!(cidr.contains(ip("10.255.0.1")));
+        assert!(!cidr.contains(ip("11.0.0.1")));
+        assert!(cidr.contains(ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let everything = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(everything.contains(ip("1.2.3.4")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+        assert!("::/0".parse::<Cidr>().unwrap().contains(ip("2001:db8::1")));
+
+        let single = "1.2.3.4".parse::<Cidr>().unwrap();
+        assert!(single.contains(ip("1.2.3.4")));
+        assert!(!single.contains(ip("1.2.3.5")));
+        let single = "::1/128".parse::<Cidr>().unwrap();
+        assert!(single.contains(ip("::1")));
+        assert!(!single.contains(ip("::2")));
+
+        // mapped networks are IPv4 networks, so their prefix can't go past 32
+        assert!("::ffff:10.0.0.0/8"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(ip("10.0.0.1")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("localhost/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn masks_prefixes() {
+        let v4 = Ipv4Addr::new(192, 168, 1, 1);
+        assert_eq!(mask_v4(v4, 0), 0);
+        assert_eq!(mask_v4(v4, 16), u32::from(Ipv4Addr::new(192, 168, 0, 0)));
+        assert_eq!(mask_v4(v4, 32), u32::from(v4));
+
+        let v6 = "2001:db8:1:2:3:4:5:6".parse::<Ipv6Addr>().unwrap();
+        assert_eq!(mask_v6(v6, 0), 0);
+        assert_eq!(
+            mask_v6(v6, 64),
+            u128::from("2001:db8:1:2::".parse::<Ipv6Addr>().unwrap())
+        );
+        assert_eq!(mask_v6(v6, 128), u128::from(v6));
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(parse_ip("1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_ip("1.2.3.4:8080"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_ip("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_ip("[2001:db8::1]"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_ip("[2001:db8::1]:4711"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_ip("::ffff:1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_ip("[::ffff:1.2.3.4]:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_ip("unknown"), None);
+        assert_eq!(parse_ip("_hidden"), None);
+        assert_eq!(parse_ip(""), None);
+    }
+
+    #[test]
+    fn picks_clients_out_of_chains() {
+        let conf = conf();
+        // anything left of the last untrusted address may have been made up by the client
+        assert_eq!(
+            conf.chain_client(vec![ip("6.6.6.6"), ip("1.2.3.4"), ip("10.0.0.2")]),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            conf.chain_client(vec![ip("10.0.0.5"), ip("192.168.1.1")]),
+            Some(ip("10.0.0.5"))
+        );
+        assert_eq!(conf.chain_client(vec![]), None);
+    }
+
+    #[test]
+    fn groups_ipv6_clients() {
+        let conf = conf();
+        assert_eq!(conf.client(ip("1.2.3.4")).to_string(), "1.2.3.4");
+        assert_eq!(conf.client(ip("::ffff:1.2.3.4")).to_string(), "1.2.3.4");
+        assert_eq!(
+            conf.client(ip("2001:db8:1:2:3:4:5:6")).to_string(),
+            "2001:db8:1:2::/64"
+        );
+        let exact = ProxyConf {
+            ipv6_prefix: 128,
+            ..conf
+        };
+        assert_eq!(exact.client(ip("2001:db8::1")).to_string(), "2001:db8::1");
+    }
+
+    #[test]
+    fn resolves_clients_through_proxies() {
+        let conf = conf();
+        let client = Client::untracked(rocket::build()).unwrap();
+        let resolve = |peer: &str, headers: &[(&'static str, &'static str)]| {
+            let mut request = client.get("/").remote(peer.parse().unwrap());
+            for (name, value) in headers {
+                request = request.header(Header::new(*name, *value));
+            }
+            conf.resolve(request.inner())
+        };
+
+        // untrusted peers can't pick their own address
+        assert_eq!(
+            resolve("8.8.8.8:1234", &[("X-Forwarded-For", "1.2.3.4")]),
+            Some(ip("8.8.8.8"))
+        );
+        assert_eq!(
+            resolve(
+                "10.0.0.1:1234",
+                &[("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 10.0.0.2")]
+            ),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            resolve(
+                "10.0.0.1:1234",
+                &[
+                    ("X-Forwarded-For", "6.6.6.6"),
+                    ("X-Forwarded-For", "1.2.3.4")
+                ]
+            ),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(
+            resolve(
+                "[::1]:1234",
+                &[(
+                    "Forwarded",
+                    "for=\"[2001:db8::1]:4711\";proto=https, for=10.0.0.2"
+                )]
+            ),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(
+            resolve("10.0.0.1:1234", &[("Forwarded", "For=192.0.2.60:8080")]),
+            Some(ip("192.0.2.60"))
+        );
+        // headers which don't have an address fall through to the next one, then to the peer
+        assert_eq!(
+            resolve(
+                "10.0.0.1:1234",
+                &[("X-Forwarded-For", "unknown"), ("X-Real-IP", "1.2.3.4")]
+            ),
+            Some(ip("1.2.3.4"))
+        );
+        assert_eq!(resolve("[::ffff:10.0.0.1]:1234", &[]), Some(ip("10.0.0.1")));
+    }
+}
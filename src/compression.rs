@@ -0,0 +1,262 @@
+use std::{
+    convert::Infallible,
+    env,
+    fmt::{self, Debug},
+    io::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression};
+use lru::LruCache;
+use rocket::{
+    http::ContentType,
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+/// The maximum size of files which get compressed on the fly
+pub const MAX_COMPRESSED_SIZE: u64 = 8 * 1024 * 1024;
+/// The default maximum size of the cache of compressed files, 32MiB
+const DEFAULT_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// A content encoding Effis can serve
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// The value of the `Content-Encoding` header for this encoding
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    /// The extension of precompressed siblings of files in this encoding
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+}
+
+/// The preferred encoding of the client, negotiated from the `Accept-Encoding` header
+#[derive(Debug, Clone, Copy)]
+pub struct AcceptEncoding(pub Option<Encoding>);
+
+impl AcceptEncoding {
+    fn parse(header: &str) -> AcceptEncoding {
+        let mut brotli = None;
+        let mut gzip = None;
+        let mut any = None;
+        for directive in header.split(',') {
+            let mut parts = directive.split(';');
+            let coding = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            match coding {
+                "br" => brotli = Some(quality),
+                "gzip" => gzip = Some(quality),
+                "*" => any = Some(quality),
+                _ => {}
+            }
+        }
+        // `*` only covers the codings which weren't listed, so an explicit `q=0` always wins
+        let brotli = brotli.or(any).unwrap_or(0.0);
+        let gzip = gzip.or(any).unwrap_or(0.0);
+        // Brotli wins ties since it compresses better
+        let encoding = if brotli > 0.0 && brotli >= gzip {
+            Some(Encoding::Brotli)
+        } else if gzip > 0.0 {
+            Some(Encoding::Gzip)
+        } else {
+            None
+        };
+        AcceptEncoding(encoding)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AcceptEncoding {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(
+            request
+                .headers()
+                .get_one("Accept-Encoding")
+                .map(AcceptEncoding::parse)
+                .unwrap_or(AcceptEncoding(None)),
+        )
+    }
+}
+
+/// Whether compressing files of this content type is worth it
+pub fn is_compressible(content_type: &ContentType) -> bool {
+    let (top, sub) = (content_type.top().as_str(), content_type.sub().as_str());
+    top == "text"
+        || sub == "json"
+        || sub == "javascript"
+        || sub == "xml"
+        || sub.ends_with("+xml")
+        || sub.ends_with("+json")
+        || sub == "wasm"
+}
+
+/// Compresses data with the provided encoding
+pub async fn compress(data: Vec<u8>, encoding: Encoding) -> io::Result<Vec<u8>> {
+    rocket::tokio::task::spawn_blocking(move || match encoding {
+        Encoding::Brotli => {
+            let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+            writer.write_all(&data)?;
+            Ok(writer.into_inner())
+        }
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()
+        }
+    })
+    .await
+    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+/// A compressed file along with the version of the file it was compressed from
+struct CompressedFile {
+    version: String,
+    data: Arc<[u8]>,
+}
+
+struct CompressionCacheState {
+    files: LruCache<(String, Encoding), CompressedFile>,
+    size: usize,
+}
+
+/// An in-memory LRU of files compressed on the fly, bounded by bytes, so that popular files
+/// aren't compressed again on every request
+///
+/// Entries are looked up by the version of their file, replaced files never get served from it
+pub struct CompressionCache {
+    max_size: usize,
+    state: Mutex<CompressionCacheState>,
+}
+
+impl Debug for CompressionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompressionCache")
+            .field("max_size", &self.max_size)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CompressionCache {
+    pub fn new(max_size: usize) -> CompressionCache {
+        CompressionCache {
+            max_size,
+            state: Mutex::new(CompressionCacheState {
+                files: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Creates a cache configured with the `EFFIS_COMPRESSION_CACHE_SIZE` environment variable, a
+    /// size of 0 disables it
+    pub fn from_env() -> Result<CompressionCache, anyhow::Error> {
+        let max_size = match env::var("EFFIS_COMPRESSION_CACHE_SIZE") {
+            Ok(size) => size
+                .parse::<usize>()
+                .context("Invalid \"EFFIS_COMPRESSION_CACHE_SIZE\" environment variable")?,
+            Err(_) => DEFAULT_CACHE_SIZE,
+        };
+        Ok(CompressionCache::new(max_size))
+    }
+
+    /// Gets a compressed file if it was compressed from the same version of the file
+    pub fn get(&self, name: &str, encoding: Encoding, version: &str) -> Option<Arc<[u8]>> {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state
+            .files
+            .get(&(name.to_string(), encoding))
+            .filter(|f| f.version == version)
+            .map(|f| Arc::clone(&f.data))
+    }
+
+    pub fn insert(&self, name: &str, encoding: Encoding, version: String, data: Arc<[u8]>) {
+        let size = name.len() + version.len() + data.len();
+        if size > self.max_size {
+            return;
+        }
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(old) = state.files.put(
+            (name.to_string(), encoding),
+            CompressedFile { version, data },
+        ) {
+            state.size -= name.len() + old.version.len() + old.data.len();
+        }
+        state.size += size;
+        while state.size > self.max_size {
+            match state.files.pop_lru() {
+                Some(((name, _), evicted)) => {
+                    state.size -= name.len() + evicted.version.len() + evicted.data.len()
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{AcceptEncoding, CompressionCache, Encoding};
+
+    #[test]
+    fn negotiates_encodings() {
+        for (header, expected) in [
+            ("gzip", Some(Encoding::Gzip)),
+            ("gzip, br", Some(Encoding::Brotli)),
+            ("br;q=0.5, gzip", Some(Encoding::Gzip)),
+            ("*", Some(Encoding::Brotli)),
+            ("gzip;q=0, *", Some(Encoding::Brotli)),
+            ("br;q=0, gzip;q=0, *", None),
+            ("*;q=0", None),
+            ("deflate, identity", None),
+            ("", None),
+        ] {
+            assert_eq!(AcceptEncoding::parse(header).0, expected, "{}", header);
+        }
+    }
+
+    #[test]
+    fn caches_compressed_files_by_version() {
+        let cache = CompressionCache::new(64);
+        let data: Arc<[u8]> = vec![0; 16].into();
+        cache.insert("a.txt", Encoding::Gzip, "1".to_string(), Arc::clone(&data));
+        assert!(cache.get("a.txt", Encoding::Gzip, "1").is_some());
+        assert!(cache.get("a.txt", Encoding::Brotli, "1").is_none());
+        assert!(cache.get("a.txt", Encoding::Gzip, "2").is_none());
+
+        // each entry takes up 22 bytes, so the third one evicts the least recently used
+        cache.insert("b.txt", Encoding::Gzip, "1".to_string(), Arc::clone(&data));
+        cache.get("a.txt", Encoding::Gzip, "1");
+        cache.insert("c.txt", Encoding::Gzip, "1".to_string(), Arc::clone(&data));
+        assert!(cache.get("a.txt", Encoding::Gzip, "1").is_some());
+        assert!(cache.get("b.txt", Encoding::Gzip, "1").is_none());
+        assert!(cache.get("c.txt", Encoding::Gzip, "1").is_some());
+    }
+}
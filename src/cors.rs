@@ -1,8 +1,116 @@
+use std::env;
+
+use anyhow::Context;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::{Header, Method, Status};
 use rocket::{Request, Response};
 
-pub struct Cors;
+/// The methods allowed by default
+const DEFAULT_METHODS: &str = "GET, POST, PUT, DELETE, OPTIONS";
+/// The response headers exposed to clients by default
+const DEFAULT_EXPOSE_HEADERS: &str = "Content-Disposition, Digest, Retry-After, \
+RateLimit-Limit, RateLimit-Remaining, RateLimit-Reset, RateLimit-Policy, \
+RateLimit-Bytes-Remaining, X-Ratelimit-Tier, X-Ratelimit-Reset, X-Ratelimit-Max, \
+X-Ratelimit-Bytes-Left, X-Ratelimit-Last-Reset, X-Ratelimit-Request-Count, \
+X-Ratelimit-Sent-Bytes";
+/// The default amount of seconds browsers may cache preflight responses for, a day
+const DEFAULT_MAX_AGE: u64 = 60 * 60 * 24;
+
+/// Adds CORS headers to responses, configured with the `EFFIS_CORS_ORIGINS`,
+/// `EFFIS_CORS_METHODS`, `EFFIS_CORS_EXPOSE_HEADERS`, `EFFIS_CORS_ALLOW_CREDENTIALS` and
+/// `EFFIS_CORS_MAX_AGE` environment variables
+///
+/// Origins are comma separated and may contain `*` wildcards, `https://*.eludris.gay` for example
+#[derive(Debug)]
+pub struct Cors {
+    origins: Vec<String>,
+    methods: String,
+    expose_headers: String,
+    allow_credentials: bool,
+    max_age: u64,
+}
+
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+/// Whether an origin matches a pattern where `*` matches any amount of characters
+fn matches(pattern: &str, origin: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match origin.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let (last, middle) = match parts.split_last() {
+        Some(parts) => parts,
+        // there are no wildcards, so the origin has to be the pattern itself
+        None => return rest.is_empty(),
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl Cors {
+    pub fn from_env() -> Result<Cors, anyhow::Error> {
+        let origins = list(&env::var("EFFIS_CORS_ORIGINS").unwrap_or_else(|_| "*".to_string()));
+        let methods =
+            list(&env::var("EFFIS_CORS_METHODS").unwrap_or_else(|_| DEFAULT_METHODS.to_string()));
+        for method in methods.iter() {
+            method
+                .parse::<Method>()
+                .map_err(|_| anyhow::anyhow!("Unknown method {}", method))
+                .context("Invalid \"EFFIS_CORS_METHODS\" environment variable")?;
+        }
+        let expose_headers = list(
+            &env::var("EFFIS_CORS_EXPOSE_HEADERS")
+                .unwrap_or_else(|_| DEFAULT_EXPOSE_HEADERS.to_string()),
+        );
+        let allow_credentials = match env::var("EFFIS_CORS_ALLOW_CREDENTIALS") {
+            Ok(allow) => allow
+                .parse::<bool>()
+                .context("Invalid \"EFFIS_CORS_ALLOW_CREDENTIALS\" environment variable")?,
+            Err(_) => false,
+        };
+        let max_age = match env::var("EFFIS_CORS_MAX_AGE") {
+            Ok(max_age) => max_age
+                .parse::<u64>()
+                .context("Invalid \"EFFIS_CORS_MAX_AGE\" environment variable")?,
+            Err(_) => DEFAULT_MAX_AGE,
+        };
+        Ok(Cors {
+            origins,
+            methods: methods.join(", "),
+            expose_headers: expose_headers.join(", "),
+            allow_credentials,
+            max_age,
+        })
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request's origin, if it's allowed
+    fn allowed_origin(&self, origin: Option<&str>) -> Option<String> {
+        // browsers reject a wildcard origin on requests with credentials, so the origin gets
+        // echoed back instead
+        if !self.allow_credentials && self.origins.iter().any(|o| o == "*") {
+            return Some("*".to_string());
+        }
+        let origin = origin?;
+        self.origins
+            .iter()
+            .any(|pattern| matches(pattern, origin))
+            .then(|| origin.to_string())
+    }
+}
 
 #[rocket::async_trait]
 impl Fairing for Cors {
@@ -14,16 +122,117 @@ impl Fairing for Cors {
     }
 
     async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new(
-            "Access-Control-Allow-Methods",
-            "POST, GET, OPTIONS",
-        ));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        let headers = request.headers();
+        let is_preflight = request.method() == Method::Options
+            && headers.contains("Access-Control-Request-Method");
+        let origin = self.allowed_origin(headers.get_one("Origin"));
 
-        if request.method() == Method::Options && response.status() == Status::NotFound {
+        if is_preflight && response.status() == Status::NotFound {
             response.set_status(Status::NoContent);
         }
+        if origin.as_deref() != Some("*") {
+            // responses to disallowed origins lack the CORS headers, so they vary too
+            response.adjoin_raw_header("Vary", "Origin");
+        }
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return,
+        };
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin));
+        if self.allow_credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        if is_preflight {
+            response.set_header(Header::new(
+                "Access-Control-Allow-Methods",
+                self.methods.clone(),
+            ));
+            // the wildcard isn't honoured on requests with credentials, so the requested headers
+            // get echoed back instead
+            let allow_headers = headers
+                .get_one("Access-Control-Request-Headers")
+                .unwrap_or("*")
+                .to_string();
+            response.set_header(Header::new("Access-Control-Allow-Headers", allow_headers));
+            response.set_header(Header::new(
+                "Access-Control-Max-Age",
+                self.max_age.to_string(),
+            ));
+        } else if !self.expose_headers.is_empty() {
+            response.set_header(Header::new(
+                "Access-Control-Expose-Headers",
+                self.expose_headers.clone(),
+            ));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(origins: &[&str], allow_credentials: bool) -> Cors {
+        Cors {
+            origins: origins.iter().map(|o| o.to_string()).collect(),
+            methods: DEFAULT_METHODS.to_string(),
+            expose_headers: DEFAULT_EXPOSE_HEADERS.to_string(),
+            allow_credentials,
+            max_age: DEFAULT_MAX_AGE,
+        }
+    }
+
+    #[test]
+    fn matches_wildcard_origins() {
+        assert!(matches("*", "https://eludris.gay"));
+        assert!(matches("https://eludris.gay", "https://eludris.gay"));
+        assert!(!matches(
+            "https://eludris.gay",
+            "https://eludris.gay.evil.com"
+        ));
+        assert!(matches("https://*.eludris.gay", "https://app.eludris.gay"));
+        assert!(matches("https://*.eludris.gay", "https://a.b.eludris.gay"));
+        assert!(!matches("https://*.eludris.gay", "https://eludris.gay"));
+        assert!(!matches("https://*.eludris.gay", "http://app.eludris.gay"));
+        assert!(!matches(
+            "https://*.eludris.gay",
+            "https://app.eludris.gay.evil.com"
+        ));
+        assert!(matches("http://localhost:*", "http://localhost:3000"));
+        assert!(matches("https://*.*.gay", "https://a.eludris.gay"));
+        // the parts around a wildcard can't overlap
+        assert!(!matches("a*a", "a"));
+        assert!(!matches("*ab*ab", "ab"));
+    }
+
+    #[test]
+    fn allows_origins() {
+        let wildcard = cors(&["*"], false);
+        assert_eq!(wildcard.allowed_origin(None).as_deref(), Some("*"));
+        assert_eq!(
+            wildcard
+                .allowed_origin(Some("https://eludris.gay"))
+                .as_deref(),
+            Some("*")
+        );
+
+        let credentials = cors(&["*"], true);
+        assert_eq!(credentials.allowed_origin(None), None);
+        assert_eq!(
+            credentials
+                .allowed_origin(Some("https://eludris.gay"))
+                .as_deref(),
+            Some("https://eludris.gay")
+        );
+
+        let listed = cors(&["https://eludris.gay", "https://*.eludris.gay"], false);
+        assert_eq!(
+            listed
+                .allowed_origin(Some("https://app.eludris.gay"))
+                .as_deref(),
+            Some("https://app.eludris.gay")
+        );
+        assert_eq!(listed.allowed_origin(Some("https://evil.com")), None);
+        assert_eq!(listed.allowed_origin(None), None);
     }
 }
@@ -0,0 +1,64 @@
+use rocket::{
+    response::{self, Responder},
+    Request,
+};
+use todel::models::{
+    ErrorResponse, ErrorResponseData, FileSizeRatelimitedError, NotFoundError, RatelimitError,
+    ServerError, ValidationError,
+};
+
+/// Every error Effis can respond with
+#[derive(Debug)]
+pub enum EffisError {
+    Ratelimited {
+        retry_after: u64,
+    },
+    FileSizeRatelimited {
+        retry_after: u64,
+        bytes_left: u64,
+    },
+    Validation {
+        field_name: String,
+        error: String,
+    },
+    NotFound,
+    Server(String),
+    /// An error returned by one of todel's models
+    Todel(ErrorResponse),
+}
+
+impl EffisError {
+    pub fn to_error_response(self) -> ErrorResponse {
+        match self {
+            EffisError::Ratelimited { retry_after } => {
+                RatelimitError { retry_after }.to_error_response()
+            }
+            EffisError::FileSizeRatelimited {
+                retry_after,
+                bytes_left,
+            } => FileSizeRatelimitedError {
+                retry_after,
+                bytes_left,
+            }
+            .to_error_response(),
+            EffisError::Validation { field_name, error } => {
+                ValidationError { field_name, error }.to_error_response()
+            }
+            EffisError::NotFound => NotFoundError.to_error_response(),
+            EffisError::Server(error) => ServerError { error }.to_error_response(),
+            EffisError::Todel(response) => response,
+        }
+    }
+}
+
+impl From<ErrorResponse> for EffisError {
+    fn from(response: ErrorResponse) -> Self {
+        EffisError::Todel(response)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for EffisError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        self.to_error_response().respond_to(request)
+    }
+}
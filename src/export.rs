@@ -0,0 +1,96 @@
+use std::path::Path;
+
+use serde::Serialize;
+use sqlx::{pool::PoolConnection, FromRow, MySql};
+use tokio::fs;
+
+use crate::storage;
+
+/// The name of the manifest written next to exported files
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+#[derive(Debug, FromRow)]
+struct ExportRow {
+    id: String,
+    file_id: String,
+    name: String,
+    spoiler: bool,
+    hash: String,
+    created_at: u64,
+}
+
+/// An exported file as listed in the manifest
+#[derive(Debug, Serialize)]
+pub struct ExportedFile {
+    pub id: String,
+    /// The path of the file relative to the export directory
+    pub path: String,
+    pub name: String,
+    pub spoiler: bool,
+    pub hash: String,
+    /// When the file was uploaded as a unix timestamp in seconds
+    pub created_at: u64,
+}
+
+/// Makes a file name safe to use as a path component
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_start_matches('.')
+        .to_string()
+}
+
+/// Copies every file of a bucket into a directory as `<id>-<name>` along with a manifest of
+/// their metadata which can be imported again, returning the exported files
+///
+/// Files whose blobs are missing are skipped
+pub async fn export_bucket(
+    bucket: &str,
+    dir: &Path,
+    db: &mut PoolConnection<MySql>,
+) -> Result<Vec<ExportedFile>, anyhow::Error> {
+    fs::create_dir_all(dir).await?;
+    let rows = sqlx::query_as::<_, ExportRow>(
+        "
+SELECT id, file_id, name, spoiler, hash, CAST(UNIX_TIMESTAMP(created_at) AS UNSIGNED) AS created_at
+FROM files
+WHERE bucket = ?
+ORDER BY id
+        ",
+    )
+    .bind(bucket)
+    .fetch_all(&mut **db)
+    .await?;
+
+    let mut files = Vec::with_capacity(rows.len());
+    for row in rows {
+        let path = format!("{}-{}", row.id, sanitize(&row.name));
+        let copied =
+            storage::with_blob(bucket, &row.file_id, |blob| fs::copy(blob, dir.join(&path))).await;
+        if let Err(err) = copied {
+            log::warn!("Skipped file {} of {}: {}", row.id, bucket, err);
+            continue;
+        }
+        files.push(ExportedFile {
+            id: row.id,
+            path,
+            name: row.name,
+            spoiler: row.spoiler,
+            hash: row.hash,
+            created_at: row.created_at,
+        });
+    }
+
+    fs::write(dir.join(MANIFEST_NAME), serde_json::to_vec_pretty(&files)?).await?;
+    log::info!(
+        "Exported {} files of {} to {}",
+        files.len(),
+        bucket,
+        dir.display()
+    );
+    Ok(files)
+}
@@ -0,0 +1,344 @@
+use std::{
+    env,
+    fmt::{self, Debug},
+    io::{self, ErrorKind},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use lru::LruCache;
+use rocket::http::ContentType;
+use sqlx::{pool::PoolConnection, MySql};
+use tokio::{fs::File, io::AsyncReadExt};
+
+use crate::{
+    checksum,
+    error::EffisError,
+    metrics::{FILE_CACHE_BYTES, FILE_CACHE_HITS, FILE_CACHE_MISSES},
+    models::StoredFile,
+    response::{content_disposition, FileBody, FileResponse},
+    storage,
+    telemetry::TraceContext,
+};
+
+/// The default maximum size of the cache, 64MiB
+const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+/// The default maximum size of file bodies kept in the cache, 1MiB
+const DEFAULT_MAX_FILE_SIZE: usize = 1024 * 1024;
+/// The default amount of seconds files stay cached for
+const DEFAULT_TTL: u64 = 60;
+
+/// A cached file, the body of which is only kept for small files
+#[derive(Debug)]
+struct CachedFile {
+    metadata: StoredFile,
+    data: Option<Arc<[u8]>>,
+    /// The `Digest` header of the file, hashed from its body when it's kept in memory
+    digest: Option<String>,
+    cached_at: Instant,
+}
+
+impl CachedFile {
+    /// The approximate amount of memory this entry takes up
+    fn size(&self) -> usize {
+        self.metadata.file_id.len()
+            + self.metadata.name.len()
+            + self.metadata.content_type.len()
+            + self.metadata.hash.len()
+            + self.data.as_ref().map(|d| d.len()).unwrap_or(0)
+            + self.digest.as_ref().map(|d| d.len()).unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+struct FileCacheState {
+    files: LruCache<(String, u128), Arc<CachedFile>>,
+    size: usize,
+}
+
+/// An in-memory LRU cache of file metadata and small file bodies, bounded by bytes
+///
+/// Entries expire after a while so that files which were removed through another instance stop
+/// being served from this one's cache even if nothing purges it
+pub struct FileCache {
+    max_size: usize,
+    max_file_size: usize,
+    ttl: Duration,
+    state: Mutex<FileCacheState>,
+}
+
+impl Debug for FileCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCache")
+            .field("max_size", &self.max_size)
+            .field("max_file_size", &self.max_file_size)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileCache {
+    pub fn new(max_size: usize, max_file_size: usize, ttl: Duration) -> FileCache {
+        FileCache {
+            max_size,
+            max_file_size: max_file_size.min(max_size),
+            ttl,
+            state: Mutex::new(FileCacheState {
+                files: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Creates a cache configured with the `EFFIS_FILE_CACHE_SIZE`,
+    /// `EFFIS_FILE_CACHE_MAX_FILE_SIZE` and `EFFIS_FILE_CACHE_TTL` environment variables, a size
+    /// of 0 disables it
+    pub fn from_env() -> Result<FileCache, anyhow::Error> {
+        let max_size = match env::var("EFFIS_FILE_CACHE_SIZE") {
+            Ok(size) => size
+                .parse::<usize>()
+                .context("Invalid \"EFFIS_FILE_CACHE_SIZE\" environment variable")?,
+            Err(_) => DEFAULT_CACHE_SIZE,
+        };
+        let max_file_size = match env::var("EFFIS_FILE_CACHE_MAX_FILE_SIZE") {
+            Ok(size) => size
+                .parse::<usize>()
+                .context("Invalid \"EFFIS_FILE_CACHE_MAX_FILE_SIZE\" environment variable")?,
+            Err(_) => DEFAULT_MAX_FILE_SIZE,
+        };
+        let ttl = match env::var("EFFIS_FILE_CACHE_TTL") {
+            Ok(ttl) => ttl
+                .parse::<u64>()
+                .context("Invalid \"EFFIS_FILE_CACHE_TTL\" environment variable")?,
+            Err(_) => DEFAULT_TTL,
+        };
+        Ok(FileCache::new(
+            max_size,
+            max_file_size,
+            Duration::from_secs(ttl),
+        ))
+    }
+
+    fn enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    fn get(&self, bucket: &str, id: u128) -> Option<Arc<CachedFile>> {
+        if !self.enabled() {
+            return None;
+        }
+        let key = (bucket.to_string(), id);
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match state.files.get(&key) {
+            Some(file) if file.cached_at.elapsed() < self.ttl => {
+                FILE_CACHE_HITS.inc();
+                return Some(Arc::clone(file));
+            }
+            Some(_) => {
+                if let Some(expired) = state.files.pop(&key) {
+                    state.size -= expired.size();
+                    FILE_CACHE_BYTES.set(state.size as i64);
+                }
+            }
+            None => {}
+        }
+        FILE_CACHE_MISSES.inc();
+        None
+    }
+
+    fn insert(&self, bucket: &str, id: u128, file: Arc<CachedFile>) {
+        let size = file.size();
+        if !self.enabled() || size > self.max_size {
+            return;
+        }
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(old) = state.files.put((bucket.to_string(), id), file) {
+            state.size -= old.size();
+        }
+        state.size += size;
+        while state.size > self.max_size {
+            match state.files.pop_lru() {
+                Some((_, evicted)) => state.size -= evicted.size(),
+                None => break,
+            }
+        }
+        FILE_CACHE_BYTES.set(state.size as i64);
+    }
+
+    /// Removes a file from the cache
+    pub fn invalidate(&self, bucket: &str, id: u128) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(file) = state.files.pop(&(bucket.to_string(), id)) {
+            state.size -= file.size();
+            FILE_CACHE_BYTES.set(state.size as i64);
+        }
+    }
+
+    /// Loads a file from the database and storage and caches it, along with its blob when its
+    /// body isn't kept in memory
+    async fn load(
+        &self,
+        id: u128,
+        bucket: &str,
+        db: &mut PoolConnection<MySql>,
+        trace: &TraceContext,
+    ) -> Result<(Arc<CachedFile>, Option<File>), EffisError> {
+        let metadata = trace
+            .instrument("StoredFile::get", StoredFile::get(id, bucket, db))
+            .await
+            .map_err(|e| {
+                log::error!("Failed to fetch file {} from {}: {}", id, bucket, e);
+                EffisError::Server("Failed to fetch file".to_string())
+            })?
+            .ok_or(EffisError::NotFound)?;
+        let mut file = open_file(bucket, &metadata.file_id, trace)
+            .await
+            .map_err(|e| file_error(bucket, &metadata.file_id, e))?;
+        let size = file
+            .metadata()
+            .await
+            .map_err(|e| file_error(bucket, &metadata.file_id, e))?
+            .len() as usize;
+
+        // streamed blobs are served as they were stored, which is what their hash is taken
+        // from, while small bodies are cheap enough to hash as they're served
+        let (data, digest, file) = if self.enabled() && size <= self.max_file_size {
+            let mut data = Vec::with_capacity(size);
+            trace
+                .instrument("fs::read", file.read_to_end(&mut data))
+                .await
+                .map_err(|e| file_error(bucket, &metadata.file_id, e))?;
+            let digest = checksum::body_digest_header(&data);
+            if checksum::digest_header(&metadata.hash).as_ref() != Some(&digest) {
+                log::warn!(
+                    "File {} of {} doesn't match its hash {}",
+                    metadata.file_id,
+                    bucket,
+                    metadata.hash
+                );
+            }
+            let data: Arc<[u8]> = data.into();
+            (Some(data), Some(digest), None)
+        } else {
+            let digest = checksum::digest_header(&metadata.hash);
+            (None, digest, Some(file))
+        };
+        let cached = Arc::new(CachedFile {
+            metadata,
+            data,
+            digest,
+            cached_at: Instant::now(),
+        });
+        self.insert(bucket, id, Arc::clone(&cached));
+        Ok((cached, file))
+    }
+
+    /// Fetches a file, serving it from memory when possible
+    pub async fn fetch(
+        &self,
+        id: u128,
+        bucket: &str,
+        disposition: &str,
+        db: &mut PoolConnection<MySql>,
+        trace: &TraceContext,
+    ) -> Result<FileResponse, EffisError> {
+        let (cached, file) = match self.get(bucket, id) {
+            Some(cached) => (cached, None),
+            None => self.load(id, bucket, db, trace).await?,
+        };
+        let body = match (&cached.data, file) {
+            (Some(data), _) => FileBody::Bytes(Arc::clone(data)),
+            (None, Some(file)) => FileBody::File(file),
+            (None, None) => FileBody::File(
+                open_file(bucket, &cached.metadata.file_id, trace)
+                    .await
+                    .map_err(|e| file_error(bucket, &cached.metadata.file_id, e))?,
+            ),
+        };
+
+        Ok(FileResponse {
+            body,
+            bucket: bucket.to_string(),
+            disposition: content_disposition(disposition, &cached.metadata.name),
+            content_type: ContentType::parse_flexible(&cached.metadata.content_type)
+                .unwrap_or(ContentType::Binary),
+            encoding: None,
+            vary_encoding: false,
+            digest: cached.digest.clone(),
+        })
+    }
+}
+
+async fn open_file(bucket: &str, file_id: &str, trace: &TraceContext) -> io::Result<File> {
+    trace
+        .instrument("fs::open", storage::with_blob(bucket, file_id, File::open))
+        .await
+}
+
+fn file_error(bucket: &str, file_id: &str, err: io::Error) -> EffisError {
+    if err.kind() == ErrorKind::NotFound {
+        log::warn!("File {} is missing from {}", file_id, bucket);
+        EffisError::NotFound
+    } else {
+        log::error!("Failed to read file {} from {}: {}", file_id, bucket, err);
+        EffisError::Server("Failed to fetch file".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::{CachedFile, FileCache};
+    use crate::models::StoredFile;
+
+    fn cached_file(size: usize) -> Arc<CachedFile> {
+        Arc::new(CachedFile {
+            metadata: StoredFile {
+                file_id: String::new(),
+                name: String::new(),
+                content_type: String::new(),
+                hash: String::new(),
+            },
+            data: Some(vec![0; size].into()),
+            digest: None,
+            cached_at: std::time::Instant::now(),
+        })
+    }
+
+    #[test]
+    fn evicts_least_recently_used_files() {
+        let cache = FileCache::new(30, 30, Duration::from_secs(60));
+        cache.insert("attachments", 1, cached_file(10));
+        cache.insert("attachments", 2, cached_file(10));
+        cache.get("attachments", 1);
+        cache.insert("attachments", 3, cached_file(20));
+        assert!(cache.get("attachments", 1).is_some());
+        assert!(cache.get("attachments", 2).is_none());
+        assert!(cache.get("attachments", 3).is_some());
+
+        cache.invalidate("attachments", 1);
+        assert!(cache.get("attachments", 1).is_none());
+        assert_eq!(cache.state.lock().unwrap().size, 20);
+    }
+
+    #[test]
+    fn expires_files() {
+        let cache = FileCache::new(30, 30, Duration::from_millis(10));
+        cache.insert("attachments", 1, cached_file(10));
+        assert!(cache.get("attachments", 1).is_some());
+        thread::sleep(Duration::from_millis(20));
+        assert!(cache.get("attachments", 1).is_none());
+        assert_eq!(cache.state.lock().unwrap().size, 0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{pool::PoolConnection, FromRow, MySql};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::{metadata_cache::MetadataCache, storage};
+
+/// How long a blob without a row is left alone for, since uploads store their blob right before
+/// inserting their row
+const ORPHAN_GRACE_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// How a storage check is run
+#[derive(Debug, Clone, Copy)]
+pub struct FsckOptions {
+    /// Remove orphaned blobs and mark the rows of missing blobs
+    pub repair: bool,
+    /// Skip hashing every blob, which is by far the slowest part of the check
+    pub skip_hashes: bool,
+}
+
+/// A file whose blob couldn't be found
+#[derive(Debug, Serialize)]
+pub struct MissingBlob {
+    pub id: String,
+    pub file_id: String,
+}
+
+/// A blob whose contents don't match the hash of its rows
+#[derive(Debug, Serialize)]
+pub struct HashMismatch {
+    pub file_id: String,
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// The problems found in a bucket
+#[derive(Debug, Default, Serialize)]
+pub struct BucketReport {
+    pub files: usize,
+    pub blobs: usize,
+    pub missing_blobs: Vec<MissingBlob>,
+    pub orphaned_blobs: Vec<PathBuf>,
+    pub hash_mismatches: Vec<HashMismatch>,
+}
+
+impl BucketReport {
+    pub fn is_clean(&self) -> bool {
+        self.missing_blobs.is_empty()
+            && self.orphaned_blobs.is_empty()
+            && self.hash_mismatches.is_empty()
+    }
+}
+
+/// The result of checking every bucket
+#[derive(Debug, Serialize)]
+pub struct FsckReport {
+    pub buckets: HashMap<String, BucketReport>,
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.buckets.values().all(BucketReport::is_clean)
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct FileRow {
+    id: String,
+    file_id: String,
+    hash: String,
+}
+
+async fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Whether a blob was modified recently enough that its row might not be inserted yet
+async fn is_recent(path: &Path) -> bool {
+    match fs::metadata(path).await.and_then(|m| m.modified()) {
+        Ok(modified) => SystemTime::now()
+            .duration_since(modified)
+            .map(|age| age < ORPHAN_GRACE_PERIOD)
+            .unwrap_or(true),
+        Err(_) => true,
+    }
+}
+
+/// Checks that every file of a bucket has a blob and every blob has a file
+pub async fn check_bucket(
+    bucket: &str,
+    options: FsckOptions,
+    db: &mut PoolConnection<MySql>,
+    cache: Option<&mut redis::aio::Connection>,
+) -> Result<BucketReport, anyhow::Error> {
+    let rows = sqlx::query_as::<_, FileRow>("SELECT id, file_id, hash FROM files WHERE bucket = ?")
+        .bind(bucket)
+        .fetch_all(&mut **db)
+        .await?;
+    let blobs: HashMap<String, PathBuf> = storage::list_blobs(bucket).await?.into_iter().collect();
+    let mut report = BucketReport {
+        files: rows.len(),
+        blobs: blobs.len(),
+        ..Default::default()
+    };
+
+    let mut checked = HashSet::new();
+    for row in rows.iter() {
+        let path = match blobs.get(&row.file_id) {
+            Some(path) => path,
+            None => {
+                report.missing_blobs.push(MissingBlob {
+                    id: row.id.clone(),
+                    file_id: row.file_id.clone(),
+                });
+                continue;
+            }
+        };
+        // files with the same contents share a blob, which only has to be hashed once
+        if options.skip_hashes || !checked.insert(&row.file_id) {
+            continue;
+        }
+        let actual = hash_file(path).await?;
+        if actual != row.hash {
+            report.hash_mismatches.push(HashMismatch {
+                file_id: row.file_id.clone(),
+                path: path.clone(),
+                expected: row.hash.clone(),
+                actual,
+            });
+        }
+    }
+
+    let referenced: HashSet<&String> = rows.iter().map(|r| &r.file_id).collect();
+    for (file_id, path) in blobs.iter() {
+        if !referenced.contains(file_id) && !is_recent(path).await {
+            report.orphaned_blobs.push(path.clone());
+        }
+    }
+
+    log::info!(
+        "Checked {} files and {} blobs of {}, {} missing, {} orphaned and {} mismatched",
+        report.files,
+        report.blobs,
+        bucket,
+        report.missing_blobs.len(),
+        report.orphaned_blobs.len(),
+        report.hash_mismatches.len()
+    );
+
+    if options.repair {
+        let cache = cache.ok_or_else(|| anyhow::anyhow!("Repairing needs a cache connection"))?;
+        repair_bucket(bucket, &report, db, cache).await?;
+    }
+    Ok(report)
+}
+
+/// Removes the orphaned blobs of a bucket and marks the files whose blobs are missing, the cached
+/// metadata of every file whose mark changes is invalidated so it isn't served from the cache
+async fn repair_bucket(
+    bucket: &str,
+    report: &BucketReport,
+    db: &mut PoolConnection<MySql>,
+    cache: &mut redis::aio::Connection,
+) -> Result<(), anyhow::Error> {
+    for path in report.orphaned_blobs.iter() {
+        match fs::remove_file(path).await {
+            Ok(()) => log::info!("Removed orphaned blob {}", path.display()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    let mut repaired: HashSet<String> =
+        sqlx::query_scalar("SELECT id FROM files WHERE bucket = ? AND missing = TRUE")
+            .bind(bucket)
+            .fetch_all(&mut **db)
+            .await?
+            .into_iter()
+            .collect();
+    sqlx::query("UPDATE files SET missing = FALSE WHERE bucket = ?")
+        .bind(bucket)
+        .execute(&mut **db)
+        .await?;
+    for file in report.missing_blobs.iter() {
+        sqlx::query("UPDATE files SET missing = TRUE WHERE id = ? AND bucket = ?")
+            .bind(&file.id)
+            .bind(bucket)
+            .execute(&mut **db)
+            .await?;
+        repaired.insert(file.id.clone());
+    }
+    for id in repaired.iter() {
+        let id: u128 = id
+            .parse()
+            .with_context(|| format!("Invalid file id {} in {}", id, bucket))?;
+        MetadataCache::invalidate(bucket, id, cache)
+            .await
+            .with_context(|| format!("Failed to purge cached metadata of file {}", id))?;
+    }
+    log::info!(
+        "Repaired {}, removed {} orphaned blobs and marked {} missing files",
+        bucket,
+        report.orphaned_blobs.len(),
+        report.missing_blobs.len()
+    );
+    Ok(())
+}
+
+/// Checks every bucket
+pub async fn check(
+    buckets: &[&str],
+    options: FsckOptions,
+    db: &mut PoolConnection<MySql>,
+    mut cache: Option<&mut redis::aio::Connection>,
+) -> Result<FsckReport, anyhow::Error> {
+    let mut report = FsckReport {
+        buckets: HashMap::new(),
+        repaired: options.repair,
+    };
+    for bucket in buckets {
+        report.buckets.insert(
+            bucket.to_string(),
+            check_bucket(bucket, options, db, cache.as_deref_mut()).await?,
+        );
+    }
+    Ok(report)
+}
@@ -0,0 +1,289 @@
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sqlx::{pool::PoolConnection, MySql};
+use todel::ids::IDGenerator;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{self, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use crate::{
+    storage::{self, FileWriter},
+    telemetry::TraceContext,
+};
+
+/// A file to import, as listed in a CSV or JSON manifest
+///
+/// The manifests written by exports can be imported as is
+#[derive(Debug, Deserialize)]
+pub struct ImportEntry {
+    /// The path of the file, relative to the manifest
+    pub path: PathBuf,
+    /// The name of the file, its file name if unset
+    pub name: Option<String>,
+    pub spoiler: Option<bool>,
+    /// When the file was originally uploaded as a unix timestamp in seconds, its modification
+    /// time if unset
+    pub created_at: Option<u64>,
+}
+
+/// A file which has been imported, recorded in the journal of an import
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    source: PathBuf,
+    id: String,
+}
+
+/// An append-only record of the files an import has gone through, which lets an interrupted
+/// import pick up where it left off
+struct Journal {
+    file: fs::File,
+    imported: HashSet<PathBuf>,
+}
+
+impl Journal {
+    async fn open(path: &Path) -> io::Result<Journal> {
+        let mut imported = HashSet::new();
+        match fs::read_to_string(path).await {
+            Ok(contents) => {
+                for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+                    match serde_json::from_str::<JournalEntry>(line) {
+                        Ok(entry) => {
+                            imported.insert(entry.source);
+                        }
+                        // the last line is cut off if the import got killed while writing it
+                        Err(err) => log::warn!("Skipping invalid journal line {:?}: {}", line, err),
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Journal { file, imported })
+    }
+
+    fn contains(&self, source: &Path) -> bool {
+        self.imported.contains(source)
+    }
+
+    async fn record(&mut self, source: &Path, id: &str) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_vec(&JournalEntry {
+            source: source.to_path_buf(),
+            id: id.to_string(),
+        })?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.file.flush().await?;
+        self.imported.insert(source.to_path_buf());
+        Ok(())
+    }
+}
+
+/// The outcome of an import
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Files which were already imported by an earlier run
+    pub skipped: usize,
+    /// Files which couldn't be imported, they're retried when the import is run again
+    pub failed: Vec<PathBuf>,
+}
+
+/// Lists every file under a directory in a stable order, skipping hidden files and directories
+async fn walk(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads a CSV or JSON manifest, CSV manifests need a header with the names of their columns
+pub async fn read_manifest(path: &Path) -> Result<Vec<ImportEntry>, anyhow::Error> {
+    let contents = fs::read(path).await?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => Ok(serde_json::from_slice(&contents)?),
+        Some("csv") => Ok(csv::Reader::from_reader(contents.as_slice())
+            .deserialize()
+            .collect::<Result<Vec<ImportEntry>, csv::Error>>()?),
+        _ => anyhow::bail!("Expected a manifest ending in .csv or .json"),
+    }
+}
+
+/// Lists the files to import from a directory or a manifest, along with the directory their
+/// paths are relative to
+async fn entries(source: &Path) -> Result<(PathBuf, Vec<ImportEntry>), anyhow::Error> {
+    if fs::metadata(source).await?.is_dir() {
+        let entries = walk(source)
+            .await?
+            .into_iter()
+            .filter_map(|path| {
+                Some(ImportEntry {
+                    path: path.strip_prefix(source).ok()?.to_path_buf(),
+                    name: None,
+                    spoiler: None,
+                    created_at: None,
+                })
+            })
+            .collect();
+        Ok((source.to_path_buf(), entries))
+    } else {
+        let base = source
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .to_path_buf();
+        Ok((base, read_manifest(source).await?))
+    }
+}
+
+/// The journal of an import when none is passed, next to its directory or manifest
+pub fn default_journal(source: &Path) -> Option<PathBuf> {
+    let name = source.file_name()?.to_string_lossy();
+    Some(source.with_file_name(format!("{}.import.jsonl", name)))
+}
+
+/// Imports a single file, returning its ID
+async fn import_file(
+    path: &Path,
+    entry: &ImportEntry,
+    bucket: &str,
+    spoiler: bool,
+    gen: &Mutex<IDGenerator>,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<String, anyhow::Error> {
+    let name = match &entry.name {
+        Some(name) => name.clone(),
+        None => path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+    };
+    let created_at = match entry.created_at {
+        Some(created_at) => created_at,
+        None => fs::metadata(path)
+            .await?
+            .modified()?
+            .duration_since(UNIX_EPOCH)?
+            .as_secs(),
+    };
+
+    let mut source = fs::File::open(path).await?;
+    let mut writer = FileWriter::create(Some(&name)).await?;
+    io::copy(&mut source, &mut writer).await?;
+    let file = writer.finish().await?;
+
+    // a file can be stored without being journaled if the import gets interrupted in between,
+    // it has the same name, contents and timestamp as its source so it isn't imported twice,
+    // which is looked up through the index on the hashes of a bucket
+    let existing = sqlx::query_as::<_, (String,)>(
+        "
+SELECT id
+FROM files
+WHERE bucket = ? AND name = ? AND hash = ? AND created_at = FROM_UNIXTIME(?)
+LIMIT 1
+        ",
+    )
+    .bind(bucket)
+    .bind(file.name())
+    .bind(file.hash())
+    .bind(created_at)
+    .fetch_optional(&mut **db)
+    .await?;
+    if let Some((id,)) = existing {
+        return Ok(id);
+    }
+
+    let spoiler = entry.spoiler.unwrap_or(spoiler);
+    let store = trace.child("storage::store");
+    let file = store
+        .run(storage::store(
+            file,
+            bucket,
+            spoiler,
+            Some(created_at),
+            gen,
+            db,
+            &store,
+        ))
+        .await
+        .map_err(|e| anyhow::anyhow!("{:?}", e.to_error_response()))?;
+    Ok(file.data.id.to_string())
+}
+
+/// Imports every file of a directory or manifest into a bucket the same way uploaded files are
+/// stored, keeping their original names and timestamps
+///
+/// Imported files are recorded in a journal so that running the import again after it got
+/// interrupted only imports the files it hadn't gotten to yet
+pub async fn import(
+    source: &Path,
+    journal: &Path,
+    bucket: &str,
+    spoiler: bool,
+    gen: &Mutex<IDGenerator>,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<ImportReport, anyhow::Error> {
+    let (base, entries) = entries(source)
+        .await
+        .with_context(|| format!("Failed to list the files of {}", source.display()))?;
+    let mut journal = Journal::open(journal)
+        .await
+        .with_context(|| format!("Failed to open journal {}", journal.display()))?;
+    let mut report = ImportReport::default();
+
+    for entry in entries.iter() {
+        if journal.contains(&entry.path) {
+            report.skipped += 1;
+            continue;
+        }
+        let path = base.join(&entry.path);
+        match import_file(&path, entry, bucket, spoiler, gen, db, trace).await {
+            Ok(id) => {
+                journal.record(&entry.path, &id).await?;
+                log::info!("Imported {} as {}", path.display(), id);
+                report.imported += 1;
+            }
+            Err(err) => {
+                log::error!("Failed to import {}: {:#}", path.display(), err);
+                report.failed.push(entry.path.clone());
+            }
+        }
+    }
+
+    log::info!(
+        "Imported {} files into {}, skipped {} and failed to import {}",
+        report.imported,
+        bucket,
+        report.skipped,
+        report.failed.len()
+    );
+    Ok(report)
+}
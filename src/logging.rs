@@ -0,0 +1,334 @@
+use std::{
+    env,
+    io::{self, Write},
+    str::FromStr,
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use log::{Level, Log, Metadata, Record};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    route::{self, Handler, Route},
+    Data, Request, Response,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{client_ip::ClientIP, metrics::RequestStart};
+
+/// The log target used for per-request access logs
+pub const ACCESS_TARGET: &str = "effis::access";
+
+/// The format logs are emitted in, configured with the `EFFIS_LOG_FORMAT` environment variable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            _ => Err(format!("Unknown log format \"{}\"", s)),
+        }
+    }
+}
+
+impl LogFormat {
+    pub fn from_env() -> Result<LogFormat, anyhow::Error> {
+        env::var("EFFIS_LOG_FORMAT")
+            .unwrap_or_else(|_| "json".to_string())
+            .parse::<LogFormat>()
+            .map_err(anyhow::Error::msg)
+            .context("Invalid \"EFFIS_LOG_FORMAT\" environment variable")
+    }
+}
+
+tokio::task_local! {
+    /// The ID of the request being handled, which every record logged while handling it carries
+    static REQUEST_ID: String;
+}
+
+/// The ID of the request being handled by the current task, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs blocking work with the ID of the request it was spawned for, so that what it logs can
+/// still be traced back to the request
+pub fn with_request_id<T>(id: Option<String>, f: impl FnOnce() -> T) -> T {
+    match id {
+        Some(id) => REQUEST_ID.sync_scope(id, f),
+        None => f(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[derive(Debug, Serialize)]
+struct LogLine<'a> {
+    timestamp: u64,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+/// A logger which writes every record as a single line of JSON to stdout
+struct JsonLogger {
+    filter: env_logger::filter::Filter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        write_line(&LogLine {
+            timestamp: now(),
+            level: record.level().as_str(),
+            target: record.target(),
+            message: record.args().to_string(),
+            request_id: current_request_id(),
+        });
+    }
+
+    fn flush(&self) {
+        io::stdout().flush().ok();
+    }
+}
+
+fn write_line<T: Serialize>(line: &T) {
+    if let Ok(line) = serde_json::to_string(line) {
+        writeln!(io::stdout().lock(), "{}", line).ok();
+    }
+}
+
+/// Sets up the global logger, filtered by `RUST_LOG` like `env_logger`
+pub fn init() -> Result<(), anyhow::Error> {
+    match LogFormat::from_env()? {
+        LogFormat::Text => env_logger::init(),
+        LogFormat::Json => {
+            let filter = env_logger::filter::Builder::from_env("RUST_LOG").build();
+            log::set_max_level(filter.filter());
+            log::set_boxed_logger(Box::new(JsonLogger { filter })).expect("Couldn't set up logger");
+        }
+    }
+    Ok(())
+}
+
+/// The ID of the current request, taken from the `X-Request-Id` header or generated
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    fn from_request(request: &Request<'_>) -> RequestId {
+        request
+            .headers()
+            .get_one("X-Request-Id")
+            .filter(|id| {
+                !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+            })
+            .map(|id| RequestId(id.to_string()))
+            .unwrap_or_else(|| RequestId(Uuid::new_v4().to_string()))
+    }
+}
+
+/// A route handler which runs the handler it wraps with the ID of its request, so that the
+/// records it logs carry it
+#[derive(Clone)]
+struct RequestIdHandler(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for RequestIdHandler {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> route::Outcome<'r> {
+        let id = request
+            .local_cache(|| RequestId::from_request(request))
+            .0
+            .clone();
+        REQUEST_ID.scope(id, self.0.handle(request, data)).await
+    }
+}
+
+/// Makes the records logged while handling requests to routes carry the ID of their request
+pub fn with_request_ids(routes: Vec<Route>) -> Vec<Route> {
+    routes
+        .into_iter()
+        .map(|mut route| {
+            route.handler = Box::new(RequestIdHandler(route.handler));
+            route
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+struct AccessLog<'a> {
+    timestamp: u64,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    request_id: &'a str,
+    method: &'a str,
+    uri: String,
+    route: Option<&'a str>,
+    bucket: Option<&'a str>,
+    file_id: Option<&'a str>,
+    client_ip: Option<String>,
+    status: u16,
+    latency_ms: f64,
+}
+
+/// Gets the value of a dynamic segment of the matched route by its name
+fn route_param<'a>(request: &'a Request<'_>, name: &str) -> Option<&'a str> {
+    let route = request.route()?;
+    let segment = format!("<{}>", name);
+    let index = route
+        .uri
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .position(|s| s == segment)?;
+    request.uri().path().segments().get(index)
+}
+
+pub struct RequestLogger {
+    format: LogFormat,
+}
+
+impl RequestLogger {
+    pub fn new(format: LogFormat) -> RequestLogger {
+        RequestLogger { format }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for RequestLogger {
+    fn info(&self) -> Info {
+        Info {
+            name: "Tag requests with IDs and log them",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestStart::record(request);
+        let id = RequestId::from_request(request);
+        request.local_cache(|| id);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let id = request.local_cache(|| RequestId::from_request(request));
+        response.set_header(Header::new("X-Request-Id", id.0.clone()));
+
+        if !log::log_enabled!(target: ACCESS_TARGET, Level::Info) {
+            return;
+        }
+
+        let latency = RequestStart::elapsed(request);
+        let route = request.route().map(|r| r.uri.as_str());
+        // Index routes implicitly use the attachments bucket
+        let bucket = route_param(request, "bucket").or_else(|| match route {
+            Some(route) if route.starts_with("/static") => Some("static"),
+            Some(_) if route_param(request, "id").is_some() => Some("attachments"),
+            _ => None,
+        });
+        let file_id = route_param(request, "id").or_else(|| route_param(request, "name"));
+        let client_ip = request
+            .guard::<ClientIP>()
+            .await
+            .succeeded()
+            .map(|ip| ip.ip().to_string());
+        let status = response.status();
+
+        match self.format {
+            LogFormat::Json => write_line(&AccessLog {
+                timestamp: now(),
+                level: Level::Info.as_str(),
+                target: ACCESS_TARGET,
+                message: format!("{} {} {}", request.method(), request.uri(), status.code),
+                request_id: &id.0,
+                method: request.method().as_str(),
+                uri: request.uri().to_string(),
+                route,
+                bucket,
+                file_id,
+                client_ip,
+                status: status.code,
+                latency_ms: latency.as_secs_f64() * 1000.0,
+            }),
+            LogFormat::Text => log::info!(
+                target: ACCESS_TARGET,
+                "{} {} {} {} {}ms [{}]",
+                request.method(),
+                request.uri(),
+                status.code,
+                client_ip.as_deref().unwrap_or("-"),
+                latency.as_millis(),
+                id.0
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::local::blocking::Client;
+
+    use super::*;
+
+    #[get("/")]
+    fn request_id() -> String {
+        current_request_id().unwrap_or_default()
+    }
+
+    #[test]
+    fn parses_log_formats() {
+        assert_eq!("json".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert_eq!("text".parse::<LogFormat>(), Ok(LogFormat::Text));
+        assert!("josn".parse::<LogFormat>().is_err());
+        assert!("".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn runs_handlers_with_their_request_id() {
+        let rocket = rocket::build().mount("/", with_request_ids(routes![request_id]));
+        let client = Client::untracked(rocket).unwrap();
+
+        let response = client
+            .get("/")
+            .header(Header::new("X-Request-Id", "abc-123"))
+            .dispatch();
+        assert_eq!(response.into_string().unwrap(), "abc-123");
+
+        // invalid IDs get replaced by generated ones
+        let response = client
+            .get("/")
+            .header(Header::new("X-Request-Id", "has spaces"))
+            .dispatch();
+        let id = response.into_string().unwrap();
+        assert!(Uuid::parse_str(&id).is_ok());
+
+        assert_eq!(current_request_id(), None);
+        assert_eq!(
+            with_request_id(Some("abc".to_string()), current_request_id),
+            Some("abc".to_string())
+        );
+        assert_eq!(with_request_id(None, current_request_id), None);
+    }
+}
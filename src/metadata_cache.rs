@@ -0,0 +1,265 @@
+use std::{collections::HashMap, env};
+
+use anyhow::Context;
+use rocket_db_pools::{
+    deadpool_redis::redis::{self, AsyncCommands, RedisResult},
+    Connection,
+};
+use sqlx::{pool::PoolConnection, MySql};
+
+use crate::{
+    error::EffisError,
+    metrics::{CACHE_ERRORS, METADATA_CACHE_HITS, METADATA_CACHE_MISSES},
+    models::{self, HashedFileData},
+    telemetry::TraceContext,
+    Cache,
+};
+
+/// The default amount of seconds file metadata stays cached for, an hour
+const DEFAULT_TTL: usize = 60 * 60;
+
+/// A cache of file metadata stored in Redis, files never change after being uploaded so entries
+/// only have to be invalidated when a file gets removed or moderated
+#[derive(Debug)]
+pub struct MetadataCache {
+    ttl: usize,
+}
+
+impl MetadataCache {
+    pub fn new(ttl: usize) -> MetadataCache {
+        MetadataCache { ttl }
+    }
+
+    /// Creates a cache configured with the `EFFIS_METADATA_CACHE_TTL` environment variable, a TTL
+    /// of 0 disables it
+    pub fn from_env() -> Result<MetadataCache, anyhow::Error> {
+        let ttl = match env::var("EFFIS_METADATA_CACHE_TTL") {
+            Ok(ttl) => ttl
+                .parse::<usize>()
+                .context("Invalid \"EFFIS_METADATA_CACHE_TTL\" environment variable")?,
+            Err(_) => DEFAULT_TTL,
+        };
+        Ok(MetadataCache::new(ttl))
+    }
+
+    fn key(bucket: &str, id: u128) -> String {
+        format!("file_data:{}:{}", bucket, id)
+    }
+
+    async fn get(
+        &self,
+        key: &str,
+        cache: &mut Connection<Cache>,
+    ) -> RedisResult<Option<HashedFileData>> {
+        let data = cache.get::<&str, Option<String>>(key).await?;
+        Ok(data.and_then(|data| match serde_json::from_str(&data) {
+            Ok(file) => Some(file),
+            Err(err) => {
+                log::warn!("Couldn't deserialize cached metadata {}: {}", key, err);
+                None
+            }
+        }))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        file: &HashedFileData,
+        cache: &mut Connection<Cache>,
+    ) -> RedisResult<()> {
+        match serde_json::to_string(file) {
+            Ok(data) => cache.set_ex::<&str, String, ()>(key, data, self.ttl).await,
+            Err(err) => {
+                log::warn!("Couldn't serialize metadata {}: {}", key, err);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fetches the metadata of a file, only querying the database if it isn't cached
+    pub async fn fetch_file_data(
+        &self,
+        id: u128,
+        bucket: &str,
+        db: &mut PoolConnection<MySql>,
+        cache: Option<&mut Connection<Cache>>,
+        trace: &TraceContext,
+    ) -> Result<HashedFileData, EffisError> {
+        let cache = cache.filter(|_| self.ttl > 0);
+        let key = MetadataCache::key(bucket, id);
+
+        let cache = match cache {
+            Some(cache) => match trace.instrument("cache::get", self.get(&key, cache)).await {
+                Ok(Some(file)) => {
+                    METADATA_CACHE_HITS.inc();
+                    return Ok(file);
+                }
+                Ok(None) => {
+                    METADATA_CACHE_MISSES.inc();
+                    Some(cache)
+                }
+                Err(err) => {
+                    log::warn!("Couldn't get cached metadata {}: {}", key, err);
+                    CACHE_ERRORS.inc();
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let file = trace
+            .instrument(
+                "fetch_file_data_batch",
+                models::fetch_file_data_batch(&[id], bucket, db),
+            )
+            .await
+            .map_err(|e| {
+                log::error!("Failed to fetch file {} from {}: {}", id, bucket, e);
+                EffisError::Server("Failed to fetch file".to_string())
+            })?
+            .pop()
+            .ok_or(EffisError::NotFound)?;
+
+        if let Some(cache) = cache {
+            if let Err(err) = trace
+                .instrument("cache::set", self.set(&key, &file, cache))
+                .await
+            {
+                log::warn!("Couldn't cache metadata {}: {}", key, err);
+                CACHE_ERRORS.inc();
+            }
+        }
+        Ok(file)
+    }
+
+    async fn get_many(
+        &self,
+        keys: &[String],
+        cache: &mut Connection<Cache>,
+    ) -> RedisResult<Vec<Option<HashedFileData>>> {
+        let data = redis::cmd("MGET")
+            .arg(keys)
+            .query_async::<_, Vec<Option<String>>>(&mut **cache)
+            .await?;
+        Ok(data
+            .into_iter()
+            .map(|data| data.and_then(|data| serde_json::from_str(&data).ok()))
+            .collect())
+    }
+
+    async fn set_many(
+        &self,
+        files: &[HashedFileData],
+        cache: &mut Connection<Cache>,
+    ) -> RedisResult<()> {
+        let mut pipe = redis::pipe();
+        for file in files {
+            if let Ok(data) = serde_json::to_string(file) {
+                pipe.set_ex(
+                    MetadataCache::key(&file.data.bucket, file.data.id),
+                    data,
+                    self.ttl,
+                )
+                .ignore();
+            }
+        }
+        pipe.query_async::<_, ()>(&mut **cache).await
+    }
+
+    /// Fetches the metadata of several files, querying the database once for the ones which
+    /// aren't cached, files which don't exist are left out
+    pub async fn fetch_file_data_batch(
+        &self,
+        ids: &[u128],
+        bucket: &str,
+        db: &mut PoolConnection<MySql>,
+        cache: Option<&mut Connection<Cache>>,
+        trace: &TraceContext,
+    ) -> Result<HashMap<u128, HashedFileData>, EffisError> {
+        let cache = cache.filter(|_| self.ttl > 0 && !ids.is_empty());
+        let mut files = HashMap::with_capacity(ids.len());
+
+        let cache = match cache {
+            Some(cache) => {
+                let keys: Vec<String> = ids
+                    .iter()
+                    .map(|id| MetadataCache::key(bucket, *id))
+                    .collect();
+                match trace
+                    .instrument("cache::get_many", self.get_many(&keys, cache))
+                    .await
+                {
+                    Ok(cached) => {
+                        for file in cached.into_iter().flatten() {
+                            files.insert(file.data.id, file);
+                        }
+                        METADATA_CACHE_HITS.inc_by(files.len() as u64);
+                        METADATA_CACHE_MISSES.inc_by((ids.len() - files.len()) as u64);
+                        Some(cache)
+                    }
+                    Err(err) => {
+                        log::warn!(
+                            "Couldn't get cached metadata of {} files: {}",
+                            ids.len(),
+                            err
+                        );
+                        CACHE_ERRORS.inc();
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let missing: Vec<u128> = ids
+            .iter()
+            .filter(|id| !files.contains_key(id))
+            .copied()
+            .collect();
+        if missing.is_empty() {
+            return Ok(files);
+        }
+        let fetched = trace
+            .instrument(
+                "fetch_file_data_batch",
+                models::fetch_file_data_batch(&missing, bucket, db),
+            )
+            .await
+            .map_err(|e| {
+                log::error!(
+                    "Failed to fetch {} files from {}: {}",
+                    missing.len(),
+                    bucket,
+                    e
+                );
+                EffisError::Server("Failed to fetch files".to_string())
+            })?;
+
+        if let Some(cache) = cache {
+            if !fetched.is_empty() {
+                if let Err(err) = trace
+                    .instrument("cache::set_many", self.set_many(&fetched, cache))
+                    .await
+                {
+                    log::warn!(
+                        "Couldn't cache metadata of {} files: {}",
+                        fetched.len(),
+                        err
+                    );
+                    CACHE_ERRORS.inc();
+                }
+            }
+        }
+        files.extend(fetched.into_iter().map(|file| (file.data.id, file)));
+        Ok(files)
+    }
+
+    /// Removes the cached metadata of a file
+    pub async fn invalidate<C: AsyncCommands>(
+        bucket: &str,
+        id: u128,
+        cache: &mut C,
+    ) -> RedisResult<()> {
+        cache.del::<&str, ()>(&MetadataCache::key(bucket, id)).await
+    }
+}
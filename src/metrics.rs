@@ -0,0 +1,304 @@
+use std::{
+    env, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{ContentType, Status},
+    Data, Orbit, Request, Response, Rocket, State,
+};
+use tokio::{
+    fs,
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{admin::Admin, storage, Cache, BUCKETS, DB};
+
+/// How often the storage usage of every bucket is measured by default, in seconds
+const DEFAULT_STORAGE_USAGE_INTERVAL: u64 = 300;
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "effis_requests_total",
+        "The amount of handled requests",
+        &["method", "route", "status"]
+    )
+    .expect("Couldn't register requests metric");
+    pub static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "effis_request_duration_seconds",
+        "The time it took to handle requests",
+        &["method", "route"]
+    )
+    .expect("Couldn't register request duration metric");
+    pub static ref UPLOADED_BYTES: IntCounterVec = register_int_counter_vec!(
+        "effis_uploaded_bytes_total",
+        "The amount of bytes uploaded to each bucket",
+        &["bucket"]
+    )
+    .expect("Couldn't register uploaded bytes metric");
+    pub static ref RATELIMITED: IntCounterVec = register_int_counter_vec!(
+        "effis_ratelimited_total",
+        "The amount of requests rejected by the ratelimiter",
+        &["bucket", "attachment_bucket"]
+    )
+    .expect("Couldn't register ratelimited metric");
+    pub static ref CACHE_ERRORS: IntCounter = register_int_counter!(
+        "effis_cache_errors_total",
+        "The amount of times the cache could not be reached while ratelimiting"
+    )
+    .expect("Couldn't register cache errors metric");
+    pub static ref DB_POOL: IntGaugeVec = register_int_gauge_vec!(
+        "effis_db_pool_connections",
+        "The state of the database connection pool",
+        &["state"]
+    )
+    .expect("Couldn't register database pool metric");
+    pub static ref CACHE_POOL: IntGaugeVec = register_int_gauge_vec!(
+        "effis_cache_pool_connections",
+        "The state of the cache connection pool",
+        &["state"]
+    )
+    .expect("Couldn't register cache pool metric");
+    pub static ref STORAGE_BYTES: IntGaugeVec = register_int_gauge_vec!(
+        "effis_storage_bytes",
+        "The amount of bytes stored in each bucket",
+        &["bucket"]
+    )
+    .expect("Couldn't register storage bytes metric");
+    pub static ref STORAGE_FILES: IntGaugeVec = register_int_gauge_vec!(
+        "effis_storage_files",
+        "The amount of files stored in each bucket",
+        &["bucket"]
+    )
+    .expect("Couldn't register storage files metric");
+    pub static ref FILE_CACHE_HITS: IntCounter = register_int_counter!(
+        "effis_file_cache_hits_total",
+        "The amount of file fetches served from the in-memory file cache"
+    )
+    .expect("Couldn't register file cache hits metric");
+    pub static ref FILE_CACHE_MISSES: IntCounter = register_int_counter!(
+        "effis_file_cache_misses_total",
+        "The amount of file fetches which missed the in-memory file cache"
+    )
+    .expect("Couldn't register file cache misses metric");
+    pub static ref FILE_CACHE_BYTES: IntGauge = register_int_gauge!(
+        "effis_file_cache_bytes",
+        "The amount of bytes held by the in-memory file cache"
+    )
+    .expect("Couldn't register file cache bytes metric");
+    pub static ref METADATA_CACHE_HITS: IntCounter = register_int_counter!(
+        "effis_metadata_cache_hits_total",
+        "The amount of file metadata fetches served from the cache"
+    )
+    .expect("Couldn't register metadata cache hits metric");
+    pub static ref METADATA_CACHE_MISSES: IntCounter = register_int_counter!(
+        "effis_metadata_cache_misses_total",
+        "The amount of file metadata fetches which missed the cache"
+    )
+    .expect("Couldn't register metadata cache misses metric");
+}
+
+/// The label of a bucket, which is only taken from a request's path once it's known to be one of
+/// the buckets so that made up buckets can't create new series
+pub fn bucket_label(bucket: &str) -> &str {
+    if bucket == "static" || BUCKETS.contains(&bucket) {
+        bucket
+    } else {
+        "unknown"
+    }
+}
+
+/// The time at which a request was received, shared by every fairing which times requests so
+/// they all measure from the same point
+pub struct RequestStart(Instant);
+
+impl RequestStart {
+    /// Records that a request was received now, unless it already was
+    pub fn record(request: &Request<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    /// The time since a request was received
+    pub fn elapsed(request: &Request<'_>) -> Duration {
+        request
+            .local_cache(|| RequestStart(Instant::now()))
+            .0
+            .elapsed()
+    }
+}
+
+/// Records request metrics and measures the storage usage of every bucket in the background,
+/// configured with the `EFFIS_STORAGE_USAGE_INTERVAL` environment variable
+///
+/// Walking every shard directory takes too long to do on each scrape, so scrapes report the
+/// usage as of the last time it was measured
+#[derive(Debug)]
+pub struct Metrics {
+    storage_usage_interval: Duration,
+}
+
+impl Metrics {
+    pub fn from_env() -> Result<Metrics, anyhow::Error> {
+        let interval = match env::var("EFFIS_STORAGE_USAGE_INTERVAL") {
+            Ok(interval) => interval
+                .parse::<u64>()
+                .ok()
+                .filter(|i| *i > 0)
+                .ok_or_else(|| anyhow::anyhow!("Expected a positive amount of seconds"))
+                .context("Invalid \"EFFIS_STORAGE_USAGE_INTERVAL\" environment variable")?,
+            Err(_) => DEFAULT_STORAGE_USAGE_INTERVAL,
+        };
+        Ok(Metrics {
+            storage_usage_interval: Duration::from_secs(interval),
+        })
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for Metrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Record request metrics",
+            kind: Kind::Liftoff | Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_liftoff(&self, _: &Rocket<Orbit>) {
+        let mut interval = time::interval(self.storage_usage_interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                record_storage_usage().await;
+            }
+        });
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestStart::record(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let elapsed = RequestStart::elapsed(request);
+        let method = request.method().as_str();
+        let route = request
+            .route()
+            .map(|r| r.uri.as_str())
+            .unwrap_or("unmatched");
+
+        REQUESTS
+            .with_label_values(&[method, route, &response.status().code.to_string()])
+            .inc();
+        REQUEST_DURATION
+            .with_label_values(&[method, route])
+            .observe(elapsed.as_secs_f64());
+    }
+}
+
+/// The size and amount of files in a directory, including the ones in shard directories
+async fn directory_usage(path: PathBuf) -> io::Result<(u64, u64)> {
+    let (mut bytes, mut files) = (0, 0);
+    let mut dirs = vec![path];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            if metadata.is_file() {
+                bytes += metadata.len();
+                files += 1;
+            } else if metadata.is_dir() {
+                dirs.push(entry.path());
+            }
+        }
+    }
+    Ok((bytes, files))
+}
+
+/// Measures the storage usage of every bucket
+async fn record_storage_usage() {
+    for bucket in BUCKETS.iter().chain(&["static"]) {
+        match directory_usage(storage::bucket_path(bucket)).await {
+            Ok((bytes, files)) => {
+                STORAGE_BYTES
+                    .with_label_values(&[*bucket])
+                    .set(bytes as i64);
+                STORAGE_FILES
+                    .with_label_values(&[*bucket])
+                    .set(files as i64);
+            }
+            Err(err) => log::warn!("Couldn't read storage usage of {}: {}", bucket, err),
+        }
+    }
+}
+
+/// Exposes every metric to Prometheus, authenticated with the admin token since the metrics
+/// tell a lot about how the server is used
+#[get("/metrics")]
+pub async fn metrics(
+    _admin: Admin,
+    db: &State<DB>,
+    cache: &State<Cache>,
+) -> Result<(ContentType, String), Status> {
+    DB_POOL.with_label_values(&["size"]).set(db.size() as i64);
+    DB_POOL
+        .with_label_values(&["idle"])
+        .set(db.num_idle() as i64);
+
+    let status = cache.status();
+    CACHE_POOL
+        .with_label_values(&["max_size"])
+        .set(status.max_size as i64);
+    CACHE_POOL
+        .with_label_values(&["size"])
+        .set(status.size as i64);
+    CACHE_POOL
+        .with_label_values(&["available"])
+        .set(status.available as i64);
+
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|err| {
+            log::error!("Couldn't encode metrics: {}", err);
+            Status::InternalServerError
+        })?;
+    let metrics = String::from_utf8(buffer).map_err(|_| Status::InternalServerError)?;
+    Ok((ContentType::Plain, metrics))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rocket::local::blocking;
+
+    use super::*;
+
+    #[test]
+    fn times_requests_from_when_they_were_first_received() {
+        let client = blocking::Client::untracked(rocket::build()).unwrap();
+        let request = client.get("/");
+        RequestStart::record(request.inner());
+        thread::sleep(Duration::from_millis(20));
+        // fairings which come later don't restart the timer
+        RequestStart::record(request.inner());
+        assert!(RequestStart::elapsed(request.inner()) >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn labels_unknown_buckets() {
+        assert_eq!(bucket_label("attachments"), "attachments");
+        assert_eq!(bucket_label("static"), "static");
+        assert_eq!(bucket_label("made-up"), "unknown");
+        assert_eq!(bucket_label(""), "unknown");
+    }
+}
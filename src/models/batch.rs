@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use todel::models::ErrorResponse;
+
+use super::HashedFileData;
+use crate::error::EffisError;
+
+/// The maximum amount of IDs which can be fetched in a single batch
+pub const MAX_BATCH_SIZE: usize = 100;
+/// The amount of IDs in a batch which count as a single request against the ratelimiter
+const IDS_PER_REQUEST: usize = 10;
+
+/// A request for the metadata of several files at once
+#[derive(Debug, Deserialize)]
+pub struct BatchFetch {
+    pub ids: Vec<u128>,
+}
+
+impl BatchFetch {
+    /// The amount of requests this batch counts as against the ratelimiter
+    pub fn weight(&self) -> u32 {
+        let ids = self.ids.len().clamp(1, MAX_BATCH_SIZE);
+        ((ids + IDS_PER_REQUEST - 1) / IDS_PER_REQUEST) as u32
+    }
+
+    /// Validates the batch, returning its deduplicated IDs
+    pub fn validate(&self) -> Result<Vec<u128>, EffisError> {
+        if self.ids.is_empty() || self.ids.len() > MAX_BATCH_SIZE {
+            return Err(EffisError::Validation {
+                field_name: "ids".to_string(),
+                error: format!("You must fetch between 1 and {} files", MAX_BATCH_SIZE),
+            });
+        }
+        let mut ids = self.ids.clone();
+        ids.sort_unstable();
+        ids.dedup();
+        Ok(ids)
+    }
+}
+
+/// The outcome of fetching a single file of a batch
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum BatchFileData {
+    File(HashedFileData),
+    Error(ErrorResponse),
+}
+
+impl BatchFileData {
+    /// Maps every requested ID to its file or to a not found error
+    pub fn from_files(
+        ids: &[u128],
+        mut files: HashMap<u128, HashedFileData>,
+    ) -> HashMap<u128, BatchFileData> {
+        ids.iter()
+            .map(|id| {
+                let data = match files.remove(id) {
+                    Some(file) => BatchFileData::File(file),
+                    None => BatchFileData::Error(EffisError::NotFound.to_error_response()),
+                };
+                (*id, data)
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,239 @@
+use serde::{Deserialize, Serialize};
+use sqlx::{pool::PoolConnection, Connection, FromRow, MySql, MySqlConnection};
+use todel::models::{File, FileData};
+
+/// The [`FileData`] of a file along with the hex encoded SHA-256 hash of its contents
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HashedFileData {
+    #[serde(flatten)]
+    pub data: FileData,
+    pub hash: String,
+}
+
+/// The metadata Effis needs to serve a stored file
+#[derive(Debug, Clone, FromRow)]
+pub struct StoredFile {
+    pub file_id: String,
+    pub name: String,
+    pub content_type: String,
+    pub hash: String,
+}
+
+impl StoredFile {
+    /// Gets the metadata of a file in a bucket, files whose blobs are missing aren't served
+    pub async fn get(
+        id: u128,
+        bucket: &str,
+        db: &mut PoolConnection<MySql>,
+    ) -> Result<Option<StoredFile>, sqlx::Error> {
+        sqlx::query_as::<_, StoredFile>(
+            "
+SELECT file_id, name, content_type, hash
+FROM files
+WHERE id = ? AND bucket = ? AND missing = FALSE
+            ",
+        )
+        .bind(id.to_string())
+        .bind(bucket)
+        .fetch_optional(&mut **db)
+        .await
+    }
+
+    /// Removes a file from a bucket, returning the ID of its blob if no other file shares it
+    ///
+    /// The rows of the blob stay locked until the file is removed, so an upload can't start
+    /// sharing a blob which is about to be removed
+    pub async fn delete(
+        id: u128,
+        bucket: &str,
+        db: &mut PoolConnection<MySql>,
+    ) -> Result<Option<String>, sqlx::Error> {
+        let mut tx = db.begin().await?;
+        let file_id = match sqlx::query_as::<_, (String,)>(
+            "SELECT file_id FROM files WHERE id = ? AND bucket = ? FOR UPDATE",
+        )
+        .bind(id.to_string())
+        .bind(bucket)
+        .fetch_optional(&mut tx)
+        .await?
+        {
+            Some((file_id,)) => file_id,
+            None => return Ok(None),
+        };
+        sqlx::query("DELETE FROM files WHERE id = ? AND bucket = ?")
+            .bind(id.to_string())
+            .bind(bucket)
+            .execute(&mut tx)
+            .await?;
+        let shared =
+            sqlx::query("SELECT id FROM files WHERE bucket = ? AND file_id = ? LIMIT 1 FOR UPDATE")
+                .bind(bucket)
+                .bind(&file_id)
+                .fetch_optional(&mut tx)
+                .await?
+                .is_some();
+        tx.commit().await?;
+        Ok((!shared).then(|| file_id))
+    }
+}
+
+/// A row of the files table, which todel's [`File`] builds the [`FileData`] of a file from
+#[derive(Debug, FromRow)]
+struct FileRow {
+    id: String,
+    file_id: String,
+    name: String,
+    content_type: String,
+    hash: String,
+    bucket: String,
+    spoiler: bool,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+impl FileRow {
+    fn into_file(self) -> Option<File> {
+        Some(File {
+            id: self.id.parse().ok()?,
+            file_id: self.file_id,
+            name: self.name,
+            content_type: self.content_type,
+            hash: self.hash,
+            bucket: self.bucket,
+            spoiler: self.spoiler,
+            width: self.width.map(|w| w as usize),
+            height: self.height.map(|h| h as usize),
+        })
+    }
+}
+
+impl From<File> for HashedFileData {
+    fn from(file: File) -> HashedFileData {
+        let hash = file.hash.clone();
+        HashedFileData {
+            data: file.get_file_data(),
+            hash,
+        }
+    }
+}
+
+/// A blob stored in a bucket, shared by every file with the same hash
+#[derive(Debug, Clone, FromRow)]
+pub struct StoredBlob {
+    pub file_id: String,
+    pub content_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl StoredBlob {
+    /// Finds the blob with a hash in a bucket
+    ///
+    /// The row it's found through stays locked until the transaction ends, which keeps the blob
+    /// from being removed before the file sharing it is inserted
+    pub async fn find(
+        hash: &str,
+        bucket: &str,
+        db: &mut MySqlConnection,
+    ) -> Result<Option<StoredBlob>, sqlx::Error> {
+        sqlx::query_as::<_, StoredBlob>(
+            "
+SELECT file_id, content_type, width, height
+FROM files
+WHERE bucket = ? AND hash = ? AND missing = FALSE
+LIMIT 1
+LOCK IN SHARE MODE
+            ",
+        )
+        .bind(bucket)
+        .bind(hash)
+        .fetch_optional(&mut *db)
+        .await
+    }
+}
+
+/// A file which is about to be added to a bucket
+#[derive(Debug)]
+pub struct NewFile {
+    pub id: u128,
+    pub file_id: String,
+    pub name: String,
+    pub content_type: String,
+    pub hash: String,
+    pub bucket: String,
+    pub spoiler: bool,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// When the file was created as a unix timestamp in seconds, now if unset
+    pub created_at: Option<u64>,
+}
+
+impl NewFile {
+    pub async fn insert(&self, db: &mut MySqlConnection) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+INSERT INTO files(id, file_id, name, content_type, hash, bucket, spoiler, width, height, created_at)
+VALUES(?, ?, ?, ?, ?, ?, ?, ?, ?, IFNULL(FROM_UNIXTIME(?), CURRENT_TIMESTAMP))
+            ",
+        )
+        .bind(self.id.to_string())
+        .bind(&self.file_id)
+        .bind(&self.name)
+        .bind(&self.content_type)
+        .bind(&self.hash)
+        .bind(&self.bucket)
+        .bind(self.spoiler)
+        .bind(self.width)
+        .bind(self.height)
+        .bind(self.created_at)
+        .execute(&mut *db)
+        .await?;
+        Ok(())
+    }
+
+    pub fn file_data(&self) -> HashedFileData {
+        File {
+            id: self.id,
+            file_id: self.file_id.clone(),
+            name: self.name.clone(),
+            content_type: self.content_type.clone(),
+            hash: self.hash.clone(),
+            bucket: self.bucket.clone(),
+            spoiler: self.spoiler,
+            width: self.width.map(|w| w as usize),
+            height: self.height.map(|h| h as usize),
+        }
+        .into()
+    }
+}
+
+/// Gets the metadata of several files in a bucket in a single query, files which don't exist or
+/// whose blobs are missing are left out
+pub async fn fetch_file_data_batch(
+    ids: &[u128],
+    bucket: &str,
+    db: &mut PoolConnection<MySql>,
+) -> Result<Vec<HashedFileData>, sqlx::Error> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let query = format!(
+        "
+SELECT id, file_id, name, content_type, hash, bucket, spoiler, width, height
+FROM files
+WHERE bucket = ? AND missing = FALSE AND id IN ({})
+        ",
+        vec!["?"; ids.len()].join(", ")
+    );
+    let mut query = sqlx::query_as::<_, FileRow>(&query).bind(bucket);
+    for id in ids {
+        query = query.bind(id.to_string());
+    }
+    Ok(query
+        .fetch_all(&mut **db)
+        .await?
+        .into_iter()
+        .filter_map(FileRow::into_file)
+        .map(HashedFileData::from)
+        .collect())
+}
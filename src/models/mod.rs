@@ -0,0 +1,7 @@
+mod batch;
+mod file;
+mod static_file;
+
+pub use batch::{BatchFetch, BatchFileData};
+pub use file::{fetch_file_data_batch, HashedFileData, NewFile, StoredBlob, StoredFile};
+pub use static_file::StaticFile;
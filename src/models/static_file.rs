@@ -0,0 +1,62 @@
+use serde::Serialize;
+use sqlx::{pool::PoolConnection, FromRow, MySql};
+
+/// An entry of the static file manifest
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct StaticFile {
+    pub name: String,
+    pub size: u64,
+    pub content_type: String,
+    pub hash: String,
+}
+
+impl StaticFile {
+    /// Lists every static file in the manifest
+    pub async fn list(db: &mut PoolConnection<MySql>) -> Result<Vec<StaticFile>, sqlx::Error> {
+        sqlx::query_as::<_, StaticFile>(
+            "SELECT name, size, content_type, hash FROM static_files ORDER BY name",
+        )
+        .fetch_all(&mut **db)
+        .await
+    }
+
+    /// Gets the manifest entry of a static file
+    pub async fn get(
+        name: &str,
+        db: &mut PoolConnection<MySql>,
+    ) -> Result<Option<StaticFile>, sqlx::Error> {
+        sqlx::query_as::<_, StaticFile>(
+            "SELECT name, size, content_type, hash FROM static_files WHERE name = ?",
+        )
+        .bind(name)
+        .fetch_optional(&mut **db)
+        .await
+    }
+
+    /// Adds the static file to the manifest, replacing any entry with the same name
+    pub async fn save(&self, db: &mut PoolConnection<MySql>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "
+INSERT INTO static_files(name, size, content_type, hash)
+VALUES(?, ?, ?, ?)
+ON DUPLICATE KEY UPDATE size = VALUES(size), content_type = VALUES(content_type), hash = VALUES(hash)
+            ",
+        )
+        .bind(&self.name)
+        .bind(self.size)
+        .bind(&self.content_type)
+        .bind(&self.hash)
+        .execute(&mut **db)
+        .await?;
+        Ok(())
+    }
+
+    /// Removes a static file from the manifest, returning whether it existed
+    pub async fn delete(name: &str, db: &mut PoolConnection<MySql>) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM static_files WHERE name = ?")
+            .bind(name)
+            .execute(&mut **db)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
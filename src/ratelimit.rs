@@ -1,49 +1,315 @@
 use std::{
-    fmt::Display,
+    collections::HashMap,
+    env,
+    str::FromStr,
+    sync::Mutex,
     time::{Duration, SystemTime},
 };
 
-use rocket::{http::Header, response::Responder};
-use rocket_db_pools::{deadpool_redis::redis::AsyncCommands, Connection};
-use todel::{
-    models::{ErrorResponse, ErrorResponseData, FileSizeRatelimitedError, RatelimitError},
-    Conf,
+use anyhow::Context;
+use rocket::{
+    http::Header,
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    Request, State,
 };
+use rocket_db_pools::{
+    deadpool_redis::redis::{self, AsyncCommands, RedisResult},
+    Connection,
+};
+use serde::{Deserialize, Serialize};
+use todel::Conf;
+
+use crate::{
+    error::EffisError,
+    metrics::{bucket_label, CACHE_ERRORS, RATELIMITED},
+    tier::Identifier,
+    Cache,
+};
+
+/// The amount of in-process buckets after which expired ones get cleaned up
+const MAX_FALLBACK_BUCKETS: usize = 10_000;
+
+/// Takes refunded bytes off of a bucket as long as it hasn't been reset since they were recorded,
+/// never going below 0
+const REFUND_SCRIPT: &str = r"
+if redis.call('HGET', KEYS[1], 'last_reset') == ARGV[1] then
+    local sent = tonumber(redis.call('HGET', KEYS[1], 'sent_bytes') or '0')
+    redis.call('HSET', KEYS[1], 'sent_bytes', math.max(sent - tonumber(ARGV[2]), 0))
+end
+";
+
+/// The current UNIX timestamp in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_millis() as u64
+}
 
-use crate::Cache;
+/// The key the buckets of an identifier are stored under
+fn bucket_key(identifier: &str, bucket: &str) -> String {
+    format!("ratelimit:{}:{}", identifier, bucket)
+}
+
+/// The key the restriction of an identifier is stored under, next to its buckets
+fn restriction_key(identifier: &str) -> String {
+    bucket_key(identifier, "restriction")
+}
 
 pub type RatelimitedRouteResponse<T> =
-    Result<RatelimitHeaderWrapper<T>, RatelimitHeaderWrapper<ErrorResponse>>;
+    Result<RatelimitHeaderWrapper<T>, RatelimitHeaderWrapper<EffisError>>;
+
+/// How requests get ratelimited while the cache is unavailable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallbackMode {
+    /// Let every request through
+    Open,
+    /// Reject every request with a server error
+    Closed,
+    /// Ratelimit requests using buckets stored in this process
+    Memory,
+}
+
+impl FromStr for FallbackMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(FallbackMode::Open),
+            "closed" => Ok(FallbackMode::Closed),
+            "memory" => Ok(FallbackMode::Memory),
+            _ => Err(format!(
+                "Unknown ratelimit fallback \"{}\", expected \"open\", \"closed\" or \"memory\"",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MemoryBucket {
+    last_reset: u64,
+    reset_after: u64,
+    request_count: u32,
+    sent_bytes: u64,
+}
+
+/// The fallback used when the cache can't be reached
+#[derive(Debug)]
+pub struct RatelimitFallback {
+    mode: FallbackMode,
+    buckets: Mutex<HashMap<String, MemoryBucket>>,
+    /// The restrictions last read from or written to the cache by their keys, which keep being
+    /// applied by the memory fallback while the cache is unavailable
+    restrictions: Mutex<HashMap<String, Restriction>>,
+}
+
+impl RatelimitFallback {
+    pub fn new(mode: FallbackMode) -> RatelimitFallback {
+        RatelimitFallback {
+            mode,
+            buckets: Mutex::new(HashMap::new()),
+            restrictions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Remembers the restriction stored under a key, or that there is none
+    fn remember_restriction(&self, key: &str, restriction: Option<&Restriction>, now: u64) {
+        let mut restrictions = self
+            .restrictions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        restrictions.retain(|_, restriction| restriction.until() > now);
+        match restriction {
+            Some(restriction) if restriction.until() > now => {
+                restrictions.insert(key.to_string(), restriction.clone());
+            }
+            _ => {
+                restrictions.remove(key);
+            }
+        }
+    }
+
+    /// The last known restriction stored under a key, if it hasn't expired yet
+    fn restriction(&self, key: &str, now: u64) -> Option<Restriction> {
+        self.restrictions
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(key)
+            .filter(|restriction| restriction.until() > now)
+            .cloned()
+    }
+}
+
+/// A cache connection, if one could be acquired, along with the fallback to use otherwise
+pub struct RatelimitCache<'r> {
+    cache: Option<Connection<Cache>>,
+    fallback: &'r RatelimitFallback,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RatelimitCache<'r> {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let fallback = try_outcome!(request.guard::<&State<RatelimitFallback>>().await);
+        let cache = request.guard::<Connection<Cache>>().await.succeeded();
+        if cache.is_none() {
+            CACHE_ERRORS.inc();
+        }
+        Outcome::Success(RatelimitCache {
+            cache,
+            fallback: fallback.inner(),
+        })
+    }
+}
+
+impl RatelimitCache<'_> {
+    /// The cache connection, if one could be acquired
+    pub fn connection(&mut self) -> Option<&mut Connection<Cache>> {
+        self.cache.as_mut()
+    }
+}
+
+/// Which ratelimit headers responses carry, configured with the
+/// `EFFIS_LEGACY_RATELIMIT_HEADERS` environment variable
+///
+/// The standard `RateLimit-*` headers are always sent, the legacy `X-Ratelimit-*` ones are
+/// sent alongside them unless they're disabled for clients which don't need them anymore
+#[derive(Debug)]
+pub struct RatelimitHeaderConf {
+    legacy: bool,
+}
 
-/// The necessary headers for responses
-#[derive(Debug, Responder)]
+impl RatelimitHeaderConf {
+    pub fn from_env() -> Result<RatelimitHeaderConf, anyhow::Error> {
+        let legacy = match env::var("EFFIS_LEGACY_RATELIMIT_HEADERS") {
+            Ok(legacy) => legacy
+                .parse::<bool>()
+                .context("Invalid \"EFFIS_LEGACY_RATELIMIT_HEADERS\" environment variable")?,
+            Err(_) => true,
+        };
+        Ok(RatelimitHeaderConf { legacy })
+    }
+}
+
+/// Rounds milliseconds up to whole seconds
+fn seconds(millis: u64) -> u64 {
+    (millis + 999) / 1000
+}
+
+/// The state of a bucket as reported to clients
+#[derive(Debug)]
+pub struct RatelimitHeaders {
+    /// The length of the bucket's window in milliseconds
+    reset_after: u64,
+    /// The amount of milliseconds until the bucket resets
+    reset_in: u64,
+    request_limit: u32,
+    request_count: u32,
+    file_size_limit: u64,
+    sent_bytes: u64,
+    last_reset: u64,
+    tier: String,
+    /// The amount of milliseconds until a ratelimited client can retry
+    retry_after: Option<u64>,
+}
+
+impl RatelimitHeaders {
+    fn headers(&self, legacy: bool) -> Vec<Header<'static>> {
+        let window = seconds(self.reset_after);
+        let bytes_left = self.file_size_limit.saturating_sub(self.sent_bytes);
+        let mut policy = format!("{};w={}", self.request_limit, window);
+        if self.file_size_limit > 0 {
+            policy.push_str(&format!(
+                ", {};w={};comment=\"bytes\"",
+                self.file_size_limit, window
+            ));
+        }
+        let mut headers = vec![
+            Header::new("RateLimit-Limit", self.request_limit.to_string()),
+            Header::new(
+                "RateLimit-Remaining",
+                self.request_limit
+                    .saturating_sub(self.request_count)
+                    .to_string(),
+            ),
+            Header::new("RateLimit-Reset", seconds(self.reset_in).to_string()),
+            Header::new("RateLimit-Policy", policy),
+            Header::new("X-Ratelimit-Tier", self.tier.clone()),
+        ];
+        if self.file_size_limit > 0 {
+            headers.push(Header::new(
+                "RateLimit-Bytes-Remaining",
+                bytes_left.to_string(),
+            ));
+        }
+        if let Some(retry_after) = self.retry_after {
+            headers.push(Header::new("Retry-After", seconds(retry_after).to_string()));
+        }
+        if legacy {
+            headers.extend([
+                Header::new("X-Ratelimit-Reset", self.reset_after.to_string()),
+                Header::new("X-Ratelimit-Max", self.request_limit.to_string()),
+                Header::new("X-Ratelimit-Bytes-Left", bytes_left.to_string()),
+                Header::new("X-Ratelimit-Last-Reset", self.last_reset.to_string()),
+                Header::new("X-Ratelimit-Request-Count", self.request_count.to_string()),
+                Header::new("X-Ratelimit-Sent-Bytes", self.sent_bytes.to_string()),
+            ]);
+        }
+        headers
+    }
+}
+
+/// A response along with the headers of the ratelimit it was processed by
+#[derive(Debug)]
 pub struct RatelimitHeaderWrapper<T> {
     pub inner: T,
-    pub ratelimit_reset: Header<'static>,
-    pub ratelimit_max: Header<'static>,
-    pub ratelimit_bytes_limit: Header<'static>,
-    pub ratelimit_last_reset: Header<'static>,
-    pub ratelimit_request_count: Header<'static>,
-    pub ratelimit_sent_bytes: Header<'static>,
+    pub headers: RatelimitHeaders,
+}
+
+impl<'r, T: Responder<'r, 'static>> Responder<'r, 'static> for RatelimitHeaderWrapper<T> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let legacy = request
+            .rocket()
+            .state::<RatelimitHeaderConf>()
+            .map(|conf| conf.legacy)
+            .unwrap_or(true);
+        let mut response = self.inner.respond_to(request)?;
+        for header in self.headers.headers(legacy) {
+            response.set_header(header);
+        }
+        Ok(response)
+    }
 }
 
 #[derive(Debug)]
 pub struct Ratelimiter {
     key: String,
+    restriction_key: String,
+    bucket: String,
+    attachment_bucket: String,
+    tier: String,
+    exempt: bool,
     reset_after: Duration,
     request_limit: u32,
     file_size_limit: u64,
     request_count: u32,
     last_reset: u64,
     sent_bytes: u64,
+    /// Whether a restriction has been applied to the limits already
+    restricted: bool,
 }
 
 impl Ratelimiter {
-    /// Creates a new Ratelimiter
-    pub fn new<I>(bucket: &str, attachment_bucket: &str, identifier: I, conf: &Conf) -> Ratelimiter
-    where
-        I: Display,
-    {
+    /// Creates a new Ratelimiter with the limits of the identifier's tier
+    pub fn new(
+        bucket: &str,
+        attachment_bucket: &str,
+        identifier: &Identifier,
+        conf: &Conf,
+    ) -> Ratelimiter {
         let (reset_after, request_limit, file_size_limit) = match bucket {
             "assets" => (
                 &conf.effis.ratelimits.assets.reset_after,
@@ -63,144 +329,681 @@ impl Ratelimiter {
 
             _ => unreachable!(),
         };
+        let identifier_key = identifier.to_string();
         Self {
-            key: format!("ratelimit:{}:{}-{}", identifier, bucket, attachment_bucket),
+            key: bucket_key(
+                &identifier_key,
+                &format!("{}-{}", bucket, attachment_bucket),
+            ),
+            restriction_key: restriction_key(&identifier_key),
+            bucket: bucket.to_string(),
+            attachment_bucket: attachment_bucket.to_string(),
+            tier: identifier.tier.name.clone(),
+            exempt: identifier.tier.is_exempt(),
             reset_after: Duration::from_secs(*reset_after as u64),
-            request_limit: *request_limit,
-            file_size_limit,
+            request_limit: identifier
+                .tier
+                .apply(*request_limit as u64)
+                .min(u32::MAX as u64) as u32,
+            file_size_limit: identifier.tier.apply(file_size_limit),
             request_count: 0,
             last_reset: 0,
             sent_bytes: 0,
+            restricted: false,
         }
     }
 
-    /// Checks if a bucket is ratelimited, if so returns an Error with an ErrorResponse
+    /// Checks if a bucket is ratelimited, if so returns an Error with an EffisError
     pub async fn process_ratelimit(
         &mut self,
         bytes: u64,
-        cache: &mut Connection<Cache>,
-    ) -> Result<(), RatelimitHeaderWrapper<ErrorResponse>> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or(Duration::ZERO)
-            .as_millis() as u64;
+        cache: &mut RatelimitCache<'_>,
+    ) -> Result<(), RatelimitHeaderWrapper<EffisError>> {
+        self.process_ratelimit_weighted(bytes, 1, cache).await
+    }
+
+    /// Checks if a bucket is ratelimited for a request which counts as `requests` requests
+    pub async fn process_ratelimit_weighted(
+        &mut self,
+        bytes: u64,
+        requests: u32,
+        cache: &mut RatelimitCache<'_>,
+    ) -> Result<(), RatelimitHeaderWrapper<EffisError>> {
+        if self.exempt {
+            return Ok(());
+        }
+        let now = now_millis();
 
         if bytes > self.file_size_limit {
-            return Err(self
-                .wrap_response::<_, ()>(
-                    FileSizeRatelimitedError {
-                        retry_after: self.last_reset + self.reset_after.as_millis() as u64 - now,
-                        bytes_left: self.file_size_limit - self.sent_bytes,
-                    }
-                    .to_error_response(),
-                )
-                .unwrap());
+            self.record_ratelimited();
+            return Err(self.wrap_error(EffisError::FileSizeRatelimited {
+                retry_after: self.retry_after(now),
+                bytes_left: self.bytes_left(),
+            }));
         }
 
-        if let (Some(last_reset), Some(request_count), Some(sent_bytes)) = cache
-            .hget::<&str, (&str, &str, &str), (Option<u64>, Option<u32>, Option<u64>)>(
-                &self.key,
-                ("last_reset", "request_count", "sent_bytes"),
+        if let Some(connection) = cache.cache.as_mut() {
+            match self
+                .process_cache(now, bytes, requests, connection, cache.fallback)
+                .await
+            {
+                Ok(result) => return result,
+                Err(err) => {
+                    log::warn!("Couldn't query cache for bucket {}: {}", self.key, err);
+                    CACHE_ERRORS.inc();
+                }
+            }
+        }
+
+        match cache.fallback.mode {
+            FallbackMode::Open => {
+                log::warn!("Cache unavailable, letting {} through", self.key);
+                Ok(())
+            }
+            FallbackMode::Closed => {
+                log::warn!("Cache unavailable, rejecting {}", self.key);
+                Err(self.wrap_error(EffisError::Server(
+                    "Could not process ratelimit".to_string(),
+                )))
+            }
+            FallbackMode::Memory => {
+                log::warn!("Cache unavailable, ratelimiting {} in memory", self.key);
+                self.process_memory(now, bytes, requests, cache.fallback)
+            }
+        }
+    }
+
+    /// Adds bytes to a bucket which was already processed, for requests which only know how many
+    /// bytes they send once they've been handled
+    pub async fn record_bytes(&mut self, bytes: u64, cache: &mut RatelimitCache<'_>) {
+        if self.exempt {
+            return;
+        }
+        self.sent_bytes = self.sent_bytes.saturating_add(bytes);
+        if let Some(connection) = cache.cache.as_mut() {
+            match connection
+                .hincr::<&str, &str, u64, ()>(&self.key, "sent_bytes", bytes)
+                .await
+            {
+                Ok(()) => return,
+                Err(err) => {
+                    log::warn!("Couldn't record bytes for bucket {}: {}", self.key, err);
+                    CACHE_ERRORS.inc();
+                }
+            }
+        }
+        if cache.fallback.mode == FallbackMode::Memory {
+            let mut buckets = cache
+                .fallback
+                .buckets
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(bucket) = buckets.get_mut(&self.key) {
+                bucket.sent_bytes = bucket.sent_bytes.saturating_add(bytes);
+            }
+        }
+    }
+
+    /// Gives back bytes which were recorded up front but never sent, unless the bucket has been
+    /// reset since
+    pub async fn refund_bytes(&mut self, bytes: u64, cache: &mut RatelimitCache<'_>) {
+        if self.exempt || bytes == 0 {
+            return;
+        }
+        self.sent_bytes = self.sent_bytes.saturating_sub(bytes);
+        if let Some(connection) = cache.cache.as_mut() {
+            match redis::cmd("EVAL")
+                .arg(REFUND_SCRIPT)
+                .arg(1)
+                .arg(&self.key)
+                .arg(self.last_reset)
+                .arg(bytes)
+                .query_async::<_, ()>(&mut **connection)
+                .await
+            {
+                Ok(()) => return,
+                Err(err) => {
+                    log::warn!("Couldn't refund bytes for bucket {}: {}", self.key, err);
+                    CACHE_ERRORS.inc();
+                }
+            }
+        }
+        if cache.fallback.mode == FallbackMode::Memory {
+            let mut buckets = cache
+                .fallback
+                .buckets
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Some(bucket) = buckets.get_mut(&self.key) {
+                if bucket.last_reset == self.last_reset {
+                    bucket.sent_bytes = bucket.sent_bytes.saturating_sub(bytes);
+                }
+            }
+        }
+    }
+
+    /// Processes the ratelimit using the bucket stored in the cache
+    async fn process_cache(
+        &mut self,
+        now: u64,
+        bytes: u64,
+        requests: u32,
+        cache: &mut Connection<Cache>,
+        fallback: &RatelimitFallback,
+    ) -> RedisResult<Result<(), RatelimitHeaderWrapper<EffisError>>> {
+        let (bucket, restriction) = redis::pipe()
+            .hget(&self.key, ("last_reset", "request_count", "sent_bytes"))
+            .get(&self.restriction_key)
+            .query_async::<_, ((Option<u64>, Option<u32>, Option<u64>), Option<String>)>(
+                &mut **cache,
             )
-            .await
-            .expect("Couldn't query cache")
-        {
+            .await?;
+        let restriction = restriction.and_then(|r| serde_json::from_str::<Restriction>(&r).ok());
+        fallback.remember_restriction(&self.restriction_key, restriction.as_ref(), now);
+        if let Err(err) = self.apply_restriction(restriction, now) {
+            return Ok(Err(err));
+        }
+
+        if let (Some(last_reset), Some(request_count), Some(sent_bytes)) = bucket {
             self.last_reset = last_reset;
             self.request_count = request_count;
             self.sent_bytes = sent_bytes;
-            if now - self.last_reset >= self.reset_after.as_millis() as u64 {
-                cache
-                    .del::<&str, ()>(&self.key)
-                    .await
-                    .expect("Couldn't query cache");
+            if now.saturating_sub(self.last_reset) >= self.reset_after.as_millis() as u64 {
+                cache.del::<&str, ()>(&self.key).await?;
                 cache
                     .hset_multiple::<&str, &str, u64, ()>(
                         &self.key,
                         &[("last_reset", now), ("request_count", 0)],
                     )
-                    .await
-                    .expect("Couldn't query cache");
+                    .await?;
                 self.last_reset = now;
                 self.request_count = 0;
                 self.sent_bytes = 0;
                 log::debug!("Reset bucket for {}", self.key);
             }
-            if self.request_count >= self.request_limit {
-                log::info!("Ratelimited bucket {}", self.key);
-                Err(self
-                    .wrap_response::<_, ()>(
-                        RatelimitError {
-                            retry_after: self.last_reset + self.reset_after.as_millis() as u64
-                                - now,
-                        }
-                        .to_error_response(),
-                    )
-                    .unwrap())
-            } else if self.sent_bytes + bytes > self.file_size_limit {
-                Err(self
-                    .wrap_response::<_, ()>(
-                        FileSizeRatelimitedError {
-                            retry_after: self.last_reset + self.reset_after.as_millis() as u64
-                                - now,
-                            bytes_left: self.file_size_limit - self.sent_bytes,
-                        }
-                        .to_error_response(),
-                    )
-                    .unwrap())
-            } else {
+            let result = self.check_bucket(now, bytes, requests);
+            if result.is_ok() {
                 cache
-                    .hincr::<&str, &str, u8, ()>(&self.key, "request_count", 1)
-                    .await
-                    .expect("Couldn't query cache");
-                self.request_count += 1;
+                    .hincr::<&str, &str, u32, ()>(&self.key, "request_count", requests)
+                    .await?;
+                self.request_count = self.request_count.saturating_add(requests);
                 cache
                     .hincr::<&str, &str, u64, ()>(&self.key, "sent_bytes", bytes)
-                    .await
-                    .expect("Couldn't query cache");
-                self.sent_bytes += bytes;
-                Ok(())
+                    .await?;
+                self.sent_bytes = self.sent_bytes.saturating_add(bytes);
             }
+            Ok(result)
         } else {
             log::debug!("New bucket for {}", self.key);
+            self.last_reset = now;
+            let result = self.check_bucket(now, bytes, requests);
+            if result.is_ok() {
+                self.request_count = requests;
+                self.sent_bytes = bytes;
+            }
             cache
                 .hset_multiple::<&str, &str, u64, ()>(
                     &self.key,
                     &[
                         ("last_reset", now),
-                        ("request_count", 1),
-                        ("sent_bytes", bytes),
+                        ("request_count", self.request_count as u64),
+                        ("sent_bytes", self.sent_bytes),
                     ],
                 )
-                .await
-                .expect("Couldn't query cache");
+                .await?;
+            Ok(result)
+        }
+    }
+
+    /// Processes the ratelimit using the in-process fallback buckets
+    fn process_memory(
+        &mut self,
+        now: u64,
+        bytes: u64,
+        requests: u32,
+        fallback: &RatelimitFallback,
+    ) -> Result<(), RatelimitHeaderWrapper<EffisError>> {
+        // restrictions are stored in the cache, so the last ones read from it are used instead
+        self.apply_restriction(fallback.restriction(&self.restriction_key, now), now)?;
+        let mut buckets = fallback
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_FALLBACK_BUCKETS {
+            buckets.retain(|_, bucket| now.saturating_sub(bucket.last_reset) < bucket.reset_after);
+        }
+        let bucket = buckets
+            .entry(self.key.clone())
+            .or_insert_with(|| MemoryBucket {
+                last_reset: now,
+                reset_after: self.reset_after.as_millis() as u64,
+                request_count: 0,
+                sent_bytes: 0,
+            });
+        if now.saturating_sub(bucket.last_reset) >= bucket.reset_after {
+            bucket.last_reset = now;
+            bucket.request_count = 0;
+            bucket.sent_bytes = 0;
+        }
+        self.last_reset = bucket.last_reset;
+        self.request_count = bucket.request_count;
+        self.sent_bytes = bucket.sent_bytes;
+        let result = self.check_bucket(now, bytes, requests);
+        if result.is_ok() {
+            self.request_count = self.request_count.saturating_add(requests);
+            self.sent_bytes = self.sent_bytes.saturating_add(bytes);
+            bucket.request_count = self.request_count;
+            bucket.sent_bytes = self.sent_bytes;
+        }
+        result
+    }
+
+    /// Applies the restriction of the identifier to the limits, rejecting the request if it's
+    /// banned
+    fn apply_restriction(
+        &mut self,
+        restriction: Option<Restriction>,
+        now: u64,
+    ) -> Result<(), RatelimitHeaderWrapper<EffisError>> {
+        match restriction {
+            Some(Restriction::Ban { until }) if until > now => {
+                log::info!("Rejected request from banned bucket {}", self.key);
+                self.record_ratelimited();
+                Err(self.wrap_error(EffisError::Ratelimited {
+                    retry_after: until - now,
+                }))
+            }
+            // the limits may already be throttled if the cache failed after reading the
+            // restriction and the request fell back to memory
+            Some(Restriction::Throttle { multiplier, until })
+                if until > now && !self.restricted =>
+            {
+                self.request_limit = (self.request_limit as f64 * multiplier).ceil() as u32;
+                self.file_size_limit = (self.file_size_limit as f64 * multiplier).ceil() as u64;
+                self.restricted = true;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether `requests` requests of `bytes` fit into the current state of the bucket
+    fn check_bucket(
+        &self,
+        now: u64,
+        bytes: u64,
+        requests: u32,
+    ) -> Result<(), RatelimitHeaderWrapper<EffisError>> {
+        if self.request_count.saturating_add(requests) > self.request_limit {
+            log::info!("Ratelimited bucket {}", self.key);
+            self.record_ratelimited();
+            Err(self.wrap_error(EffisError::Ratelimited {
+                retry_after: self.retry_after(now),
+            }))
+        } else if self.sent_bytes.saturating_add(bytes) > self.file_size_limit {
+            self.record_ratelimited();
+            Err(self.wrap_error(EffisError::FileSizeRatelimited {
+                retry_after: self.retry_after(now),
+                bytes_left: self.bytes_left(),
+            }))
+        } else {
             Ok(())
         }
     }
 
-    pub fn wrap_response<T, E>(&self, data: T) -> Result<RatelimitHeaderWrapper<T>, E> {
-        Ok(RatelimitHeaderWrapper {
+    /// The amount of milliseconds until the bucket resets
+    fn retry_after(&self, now: u64) -> u64 {
+        // a corrupted bucket in the cache could hold any last reset
+        self.last_reset
+            .saturating_add(self.reset_after.as_millis() as u64)
+            .saturating_sub(now)
+    }
+
+    /// The amount of bytes which can still be sent before the bucket resets
+    pub fn bytes_left(&self) -> u64 {
+        self.file_size_limit.saturating_sub(self.sent_bytes)
+    }
+
+    fn record_ratelimited(&self) {
+        RATELIMITED
+            .with_label_values(&[&self.bucket, bucket_label(&self.attachment_bucket)])
+            .inc();
+    }
+
+    /// Attaches the ratelimit headers to an error, along with when to retry if it's a ratelimit
+    pub fn wrap_error<E: Into<EffisError>>(&self, error: E) -> RatelimitHeaderWrapper<EffisError> {
+        let error = error.into();
+        let retry_after = match &error {
+            EffisError::Ratelimited { retry_after }
+            | EffisError::FileSizeRatelimited { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        };
+        let mut response = self.wrap_response(error);
+        response.headers.retry_after = retry_after;
+        response
+    }
+
+    /// Attaches the ratelimit headers to the error of a result, if any
+    pub fn check<T, E: Into<EffisError>>(
+        &self,
+        result: Result<T, E>,
+    ) -> Result<T, RatelimitHeaderWrapper<EffisError>> {
+        result.map_err(|e| self.wrap_error(e))
+    }
+
+    /// Attaches the ratelimit headers to a response
+    pub fn wrap_response<T>(&self, data: T) -> RatelimitHeaderWrapper<T> {
+        let reset_after = self.reset_after.as_millis() as u64;
+        RatelimitHeaderWrapper {
             inner: data,
-            ratelimit_reset: Header::new(
-                "X-Ratelimit-Reset",
-                self.reset_after.as_millis().to_string(),
-            ),
-            ratelimit_max: Header::new("X-Ratelimit-Max", self.request_limit.to_string()),
-            ratelimit_bytes_limit: Header::new(
-                "X-Ratelimit-Bytes-Left",
-                self.file_size_limit.to_string(),
-            ),
-            ratelimit_last_reset: Header::new(
-                "X-Ratelimit-Last-Reset",
-                self.last_reset.to_string(),
-            ),
-            ratelimit_request_count: Header::new(
-                "X-Ratelimit-Request-Count",
-                self.request_count.to_string(),
-            ),
-            ratelimit_sent_bytes: Header::new(
-                "X-Ratelimit-Sent-Bytes",
-                self.sent_bytes.to_string(),
-            ),
+            headers: RatelimitHeaders {
+                reset_after,
+                // buckets which were never processed, like those of exempt clients, reset a
+                // whole window from now
+                reset_in: if self.last_reset == 0 {
+                    reset_after
+                } else {
+                    self.retry_after(now_millis())
+                },
+                request_limit: self.request_limit,
+                request_count: self.request_count,
+                file_size_limit: self.file_size_limit,
+                sent_bytes: self.sent_bytes,
+                last_reset: self.last_reset,
+                tier: self.tier.clone(),
+                retry_after: None,
+            },
+        }
+    }
+}
+
+/// A temporary restriction put on an identifier by an administrator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Restriction {
+    /// Reject every request until the restriction expires
+    Ban { until: u64 },
+    /// Multiply every limit by a factor until the restriction expires
+    Throttle { multiplier: f64, until: u64 },
+}
+
+impl Restriction {
+    /// The UNIX timestamp in milliseconds the restriction expires at
+    pub fn until(&self) -> u64 {
+        match self {
+            Restriction::Ban { until } | Restriction::Throttle { until, .. } => *until,
+        }
+    }
+}
+
+/// A restriction as requested by an administrator, lasting `duration` seconds
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NewRestriction {
+    Ban { duration: u64 },
+    Throttle { multiplier: f64, duration: u64 },
+}
+
+impl NewRestriction {
+    /// Validates the restriction, turning it into one which expires `duration` seconds from now
+    pub fn into_restriction(self, now: u64) -> Result<Restriction, EffisError> {
+        let duration = match self {
+            NewRestriction::Ban { duration } | NewRestriction::Throttle { duration, .. } => {
+                duration
+            }
+        };
+        if duration == 0 {
+            return Err(EffisError::Validation {
+                field_name: "duration".to_string(),
+                error: "The duration must be at least a second".to_string(),
+            });
+        }
+        let until = now.saturating_add(duration.saturating_mul(1000));
+        match self {
+            NewRestriction::Ban { .. } => Ok(Restriction::Ban { until }),
+            NewRestriction::Throttle { multiplier, .. } => {
+                // throttles lower limits, anything above 1 would raise them instead
+                if !(multiplier > 0.0 && multiplier <= 1.0) {
+                    return Err(EffisError::Validation {
+                        field_name: "multiplier".to_string(),
+                        error: "The multiplier must be greater than 0 and at most 1".to_string(),
+                    });
+                }
+                Ok(Restriction::Throttle { multiplier, until })
+            }
+        }
+    }
+}
+
+/// The state of a ratelimit bucket
+#[derive(Debug, Serialize)]
+pub struct BucketState {
+    pub last_reset: Option<u64>,
+    pub request_count: Option<u32>,
+    pub sent_bytes: Option<u64>,
+}
+
+/// Every ratelimit bucket of an identifier along with its restriction, if any
+#[derive(Debug, Serialize)]
+pub struct IdentifierRatelimits {
+    pub identifier: String,
+    pub buckets: HashMap<String, BucketState>,
+    pub restriction: Option<Restriction>,
+}
+
+/// Gets the names of every bucket an identifier has
+async fn bucket_names(identifier: &str, cache: &mut Connection<Cache>) -> RedisResult<Vec<String>> {
+    let escaped: String = identifier
+        .chars()
+        .flat_map(|c| match c {
+            '*' | '?' | '[' | ']' | '\\' => vec!['\\', c],
+            c => vec![c],
         })
+        .collect();
+    let prefix = bucket_key(identifier, "");
+    let mut keys = cache
+        .scan_match::<String, String>(bucket_key(&escaped, "*"))
+        .await?;
+    let mut names = vec![];
+    while let Some(key) = keys.next_item().await {
+        // identifiers with colons could match the keys of longer identifiers
+        match key.strip_prefix(&prefix) {
+            Some(name) if !name.contains(':') && name != "restriction" => {
+                names.push(name.to_string())
+            }
+            _ => {}
+        }
+    }
+    Ok(names)
+}
+
+/// Gets every ratelimit bucket of an identifier
+pub async fn fetch_ratelimits(
+    identifier: &str,
+    cache: &mut Connection<Cache>,
+) -> RedisResult<IdentifierRatelimits> {
+    let mut buckets = HashMap::new();
+    for name in bucket_names(identifier, cache).await? {
+        let (last_reset, request_count, sent_bytes) = cache
+            .hget::<String, (&str, &str, &str), (Option<u64>, Option<u32>, Option<u64>)>(
+                bucket_key(identifier, &name),
+                ("last_reset", "request_count", "sent_bytes"),
+            )
+            .await?;
+        buckets.insert(
+            name,
+            BucketState {
+                last_reset,
+                request_count,
+                sent_bytes,
+            },
+        );
+    }
+    let restriction = cache
+        .get::<String, Option<String>>(restriction_key(identifier))
+        .await?
+        .and_then(|r| serde_json::from_str(&r).ok());
+    Ok(IdentifierRatelimits {
+        identifier: identifier.to_string(),
+        buckets,
+        restriction,
+    })
+}
+
+/// Resets every ratelimit bucket of an identifier, returning how many there were
+pub async fn reset_ratelimits(
+    identifier: &str,
+    cache: &mut Connection<Cache>,
+) -> RedisResult<usize> {
+    let keys: Vec<String> = bucket_names(identifier, cache)
+        .await?
+        .iter()
+        .map(|name| bucket_key(identifier, name))
+        .collect();
+    if !keys.is_empty() {
+        cache.del::<&[String], ()>(&keys).await?;
+    }
+    Ok(keys.len())
+}
+
+/// Restricts an identifier until the restriction expires
+pub async fn set_restriction(
+    identifier: &str,
+    restriction: &Restriction,
+    now: u64,
+    cache: &mut Connection<Cache>,
+    fallback: &RatelimitFallback,
+) -> RedisResult<()> {
+    let key = restriction_key(identifier);
+    let data = serde_json::to_string(restriction).unwrap_or_default();
+    let seconds = (restriction.until().saturating_sub(now) + 999) / 1000;
+    cache
+        .set_ex::<&str, String, ()>(&key, data, seconds.max(1) as usize)
+        .await?;
+    fallback.remember_restriction(&key, Some(restriction), now);
+    Ok(())
+}
+
+/// Lifts the restriction of an identifier, returning whether it had one
+pub async fn remove_restriction(
+    identifier: &str,
+    cache: &mut Connection<Cache>,
+    fallback: &RatelimitFallback,
+) -> RedisResult<bool> {
+    let key = restriction_key(identifier);
+    let removed = cache.del::<&str, u32>(&key).await? > 0;
+    fallback.remember_restriction(&key, None, now_millis());
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratelimiter(request_limit: u32) -> Ratelimiter {
+        Ratelimiter {
+            key: bucket_key("1.2.3.4", "attachments-attachments"),
+            restriction_key: restriction_key("1.2.3.4"),
+            bucket: "attachments".to_string(),
+            attachment_bucket: "attachments".to_string(),
+            tier: "default".to_string(),
+            exempt: false,
+            reset_after: Duration::from_secs(60),
+            request_limit,
+            file_size_limit: 1000,
+            request_count: 0,
+            last_reset: 0,
+            sent_bytes: 0,
+            restricted: false,
+        }
+    }
+
+    #[test]
+    fn applies_restrictions_in_memory() {
+        let now = now_millis();
+        let key = restriction_key("1.2.3.4");
+
+        let fallback = RatelimitFallback::new(FallbackMode::Memory);
+        let ban = Restriction::Ban { until: now + 1000 };
+        fallback.remember_restriction(&key, Some(&ban), now);
+        let err = ratelimiter(10)
+            .process_memory(now, 0, 1, &fallback)
+            .unwrap_err();
+        assert!(matches!(
+            err.inner,
+            EffisError::Ratelimited { retry_after: 1000 }
+        ));
+        // lifted restrictions stop applying
+        fallback.remember_restriction(&key, None, now);
+        assert!(ratelimiter(10).process_memory(now, 0, 1, &fallback).is_ok());
+
+        let fallback = RatelimitFallback::new(FallbackMode::Memory);
+        let throttle = Restriction::Throttle {
+            multiplier: 0.5,
+            until: now + 1000,
+        };
+        fallback.remember_restriction(&key, Some(&throttle), now);
+        assert!(ratelimiter(2).process_memory(now, 0, 1, &fallback).is_ok());
+        assert!(ratelimiter(2).process_memory(now, 0, 1, &fallback).is_err());
+        // expired restrictions aren't applied
+        assert!(ratelimiter(2)
+            .process_memory(now + 1000, 0, 1, &fallback)
+            .is_ok());
+    }
+
+    #[test]
+    fn computes_retry_after() {
+        let mut ratelimiter = ratelimiter(10);
+        ratelimiter.last_reset = 1000;
+        assert_eq!(ratelimiter.retry_after(1000), 60_000);
+        assert_eq!(ratelimiter.retry_after(31_000), 30_000);
+        assert_eq!(ratelimiter.retry_after(100_000), 0);
+        ratelimiter.last_reset = u64::MAX;
+        assert_eq!(ratelimiter.retry_after(1000), u64::MAX - 1000);
+    }
+
+    #[test]
+    fn validates_restrictions() {
+        let throttle = |multiplier| {
+            NewRestriction::Throttle {
+                multiplier,
+                duration: 60,
+            }
+            .into_restriction(1000)
+        };
+        assert!(matches!(
+            throttle(0.5),
+            Ok(Restriction::Throttle { until: 61_000, .. })
+        ));
+        assert!(throttle(1.0).is_ok());
+        for multiplier in [0.0, -1.0, 1.5, 50.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                throttle(multiplier),
+                Err(EffisError::Validation { field_name, .. }) if field_name == "multiplier"
+            ));
+        }
+        assert!(NewRestriction::Ban { duration: 0 }
+            .into_restriction(1000)
+            .is_err());
+        assert!(matches!(
+            NewRestriction::Ban { duration: 1 }.into_restriction(1000),
+            Ok(Restriction::Ban { until: 2000 })
+        ));
+    }
+
+    #[test]
+    fn throttles_once() {
+        let now = now_millis();
+        let throttle = Restriction::Throttle {
+            multiplier: 0.5,
+            until: now + 1000,
+        };
+        let mut ratelimiter = ratelimiter(8);
+        assert!(ratelimiter
+            .apply_restriction(Some(throttle.clone()), now)
+            .is_ok());
+        assert!(ratelimiter.apply_restriction(Some(throttle), now).is_ok());
+        assert_eq!(ratelimiter.request_limit, 4);
+        assert_eq!(ratelimiter.file_size_limit, 500);
     }
 }
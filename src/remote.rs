@@ -0,0 +1,427 @@
+use std::{
+    env, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use reqwest::{header, redirect::Policy, Client};
+use serde::Deserialize;
+use tokio::{io::AsyncWriteExt, net};
+use url::{Host, Url};
+
+use crate::{
+    error::EffisError,
+    storage::{FileWriter, StreamedFile},
+    telemetry::TraceContext,
+};
+
+/// The default amount of seconds a remote file has to be downloaded in
+const DEFAULT_TIMEOUT: u64 = 10;
+/// The maximum amount of redirects followed while downloading a remote file
+const MAX_REDIRECTS: usize = 5;
+
+/// A request to upload a file hosted somewhere else
+#[derive(Debug, Deserialize)]
+pub struct UrlUpload {
+    pub url: String,
+    #[serde(default)]
+    pub spoiler: bool,
+    /// The SHA-256 checksum the file is expected to have, as hex or base64
+    pub checksum: Option<String>,
+}
+
+/// How remote files are downloaded, configured with the `EFFIS_URL_UPLOAD_TIMEOUT` and
+/// `EFFIS_URL_UPLOAD_ALLOW_PRIVATE` environment variables
+#[derive(Debug)]
+pub struct RemoteConf {
+    timeout: Duration,
+    /// Whether files may be downloaded from private networks, only meant for local development
+    allow_private: bool,
+}
+
+impl RemoteConf {
+    pub fn from_env() -> Result<RemoteConf, anyhow::Error> {
+        let timeout = match env::var("EFFIS_URL_UPLOAD_TIMEOUT") {
+            Ok(timeout) => timeout
+                .parse::<u64>()
+                .context("Invalid \"EFFIS_URL_UPLOAD_TIMEOUT\" environment variable")?,
+            Err(_) => DEFAULT_TIMEOUT,
+        };
+        let allow_private = match env::var("EFFIS_URL_UPLOAD_ALLOW_PRIVATE") {
+            Ok(allow) => allow
+                .parse::<bool>()
+                .context("Invalid \"EFFIS_URL_UPLOAD_ALLOW_PRIVATE\" environment variable")?,
+            Err(_) => false,
+        };
+        Ok(RemoteConf {
+            timeout: Duration::from_secs(timeout),
+            allow_private,
+        })
+    }
+}
+
+/// Whether an IPv4 address is reachable on the public internet
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // shared address space
+        || (a == 100 && (64..128).contains(&b))
+        // protocol assignments and benchmarking
+        || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (18..20).contains(&b))
+        // reserved
+        || a >= 240)
+}
+
+/// Whether an IPv6 address is reachable on the public internet
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    // IPv4-mapped (`::ffff:0:0/96`) and IPv4-compatible (`::/96`) addresses, `::` and `::1`
+    // included, are only as public as the IPv4 address they embed
+    if let Some(ip) = ip.to_ipv4() {
+        return is_public_v4(ip);
+    }
+    let segments = ip.segments();
+    let first = segments[0];
+    // 6to4 addresses are routed to the IPv4 address in their second and third segments
+    if first == 0x2002 {
+        let [a, b] = segments[1].to_be_bytes();
+        let [c, d] = segments[2].to_be_bytes();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // unique local
+        || (first & 0xfe00) == 0xfc00
+        // link local
+        || (first & 0xffc0) == 0xfe80
+        // documentation
+        || (first == 0x2001 && segments[1] == 0x0db8)
+        // NAT64, which could be used to reach private IPv4 addresses
+        || (first == 0x0064 && segments[1] == 0xff9b))
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn url_error(error: &str) -> EffisError {
+    EffisError::Validation {
+        field_name: "url".to_string(),
+        error: error.to_string(),
+    }
+}
+
+/// Downloads a file of at most `max_size` bytes, refusing to connect to private networks
+pub async fn download(
+    url: &str,
+    max_size: u64,
+    conf: &RemoteConf,
+    trace: &TraceContext,
+) -> Result<StreamedFile, EffisError> {
+    let url = Url::parse(url).map_err(|_| url_error("Invalid URL"))?;
+    match tokio::time::timeout(
+        conf.timeout,
+        trace.instrument("remote::download", fetch(url, max_size, conf)),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(url_error("Timed out while downloading the file")),
+    }
+}
+
+/// Resolves the addresses of a URL, making sure every one of them is allowed
+async fn resolve(url: &Url, conf: &RemoteConf) -> Result<Vec<SocketAddr>, EffisError> {
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| url_error("Invalid URL"))?;
+    let addrs: Vec<SocketAddr> = match url.host() {
+        Some(Host::Domain(domain)) => net::lookup_host((domain, port))
+            .await
+            .map_err(|_| url_error("Could not resolve the URL's host"))?
+            .collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        None => return Err(url_error("Invalid URL")),
+    };
+    if addrs.is_empty() {
+        return Err(url_error("Could not resolve the URL's host"));
+    }
+    if !conf.allow_private && addrs.iter().any(|addr| !is_public(addr.ip())) {
+        log::info!("Refused to download {} from a private address", url);
+        return Err(url_error("The URL points to a disallowed address"));
+    }
+    Ok(addrs)
+}
+
+async fn fetch(mut url: Url, max_size: u64, conf: &RemoteConf) -> Result<StreamedFile, EffisError> {
+    for _ in 0..=MAX_REDIRECTS {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(url_error("Only HTTP and HTTPS URLs are supported"));
+        }
+        let addrs = resolve(&url, conf).await?;
+        // Pin the connection to the addresses which were checked so the host can't resolve
+        // to a different one in between
+        let mut client = Client::builder().redirect(Policy::none()).no_proxy();
+        if let Some(domain) = url.domain() {
+            client = client.resolve_to_addrs(domain, &addrs);
+        }
+        let client = client.build().map_err(|e| {
+            log::error!("Failed to build HTTP client: {}", e);
+            EffisError::Server("Failed to download file".to_string())
+        })?;
+
+        let response = client
+            .get(url.clone())
+            .send()
+            .await
+            .map_err(|_| url_error("Could not download the file"))?;
+        if response.status().is_redirection() {
+            url = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .and_then(|l| url.join(l).ok())
+                .ok_or_else(|| url_error("The URL redirected to an invalid location"))?;
+            continue;
+        }
+        if !response.status().is_success() {
+            return Err(url_error(&format!(
+                "The URL responded with status {}",
+                response.status().as_u16()
+            )));
+        }
+        return save(url, response, max_size).await;
+    }
+    Err(url_error("The URL redirected too many times"))
+}
+
+/// Streams the body of a response into the storage directory, enforcing the size limit while
+/// doing so
+async fn save(
+    url: Url,
+    mut response: reqwest::Response,
+    max_size: u64,
+) -> Result<StreamedFile, EffisError> {
+    let too_large = || url_error(&format!("The file is larger than {} bytes", max_size));
+    if response.content_length().unwrap_or(0) > max_size {
+        return Err(too_large());
+    }
+    let name = url
+        .path_segments()
+        .and_then(|mut s| s.next_back())
+        .filter(|n| !n.is_empty());
+
+    let write_error = |e: io::Error| {
+        log::error!("Failed to write downloaded file: {}", e);
+        EffisError::Server("Failed to download file".to_string())
+    };
+    let mut writer = FileWriter::create(name).await.map_err(write_error)?;
+    let mut len = 0;
+    loop {
+        let chunk = match response.chunk().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => return Err(url_error("Could not download the file")),
+        };
+        len += chunk.len() as u64;
+        if len > max_size {
+            return Err(too_large());
+        }
+        writer.write_all(&chunk).await.map_err(write_error)?;
+    }
+    let file = writer.finish().await.map_err(write_error)?;
+    if file.is_empty() {
+        return Err(EffisError::Validation {
+            field_name: "file".to_string(),
+            error: "You cannot upload empty files".to_string(),
+        });
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, SocketAddr},
+        time::Duration,
+    };
+
+    use sha2::{Digest, Sha256};
+    use tokio::{
+        fs,
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::{download, is_public, RemoteConf};
+    use crate::{error::EffisError, storage, telemetry::TraceContext};
+
+    fn conf(allow_private: bool) -> RemoteConf {
+        RemoteConf {
+            timeout: Duration::from_millis(500),
+            allow_private,
+        }
+    }
+
+    /// Serves raw HTTP responses picked by the path of each request, waiting a bit before
+    /// answering requests to `/slow`
+    async fn stand_in() -> SocketAddr {
+        fs::create_dir_all(&storage::layout().root).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut request = vec![0; 4096];
+                    let read = stream.read(&mut request).await.unwrap_or(0);
+                    let request = String::from_utf8_lossy(&request[..read]);
+                    let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+                    if path == "/slow" {
+                        tokio::time::sleep(Duration::from_secs(5)).await;
+                    }
+                    let response = respond(&path, addr);
+                    stream.write_all(response.as_bytes()).await.ok();
+                    stream.shutdown().await.ok();
+                });
+            }
+        });
+        addr
+    }
+
+    fn respond(path: &str, addr: SocketAddr) -> String {
+        match path {
+            "/redirect" => format!(
+                "HTTP/1.1 302 Found\r\nLocation: http://{}/cat.txt\r\nContent-Length: 0\r\n\r\n",
+                addr
+            ),
+            "/loop" => {
+                "HTTP/1.1 302 Found\r\nLocation: /loop\r\nContent-Length: 0\r\n\r\n".to_string()
+            }
+            "/file" => {
+                "HTTP/1.1 302 Found\r\nLocation: file:///etc/passwd\r\nContent-Length: 0\r\n\r\n"
+                    .to_string()
+            }
+            // no content length so that the limit is enforced while streaming
+            "/large" => format!(
+                "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n{}",
+                "a".repeat(64)
+            ),
+            _ => "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nmeow!".to_string(),
+        }
+    }
+
+    fn error_message(result: Result<storage::StreamedFile, EffisError>) -> String {
+        match result {
+            Err(EffisError::Validation { error, .. }) => error,
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn downloads_files() {
+        let addr = stand_in().await;
+        let trace = TraceContext::start("test");
+        let file = download(&format!("http://{}/cat.txt", addr), 64, &conf(true), &trace)
+            .await
+            .unwrap();
+        assert_eq!(file.len(), 5);
+        assert_eq!(file.hash(), format!("{:x}", Sha256::digest(b"meow!")));
+
+        let file = download(
+            &format!("http://{}/redirect", addr),
+            64,
+            &conf(true),
+            &trace,
+        )
+        .await
+        .unwrap();
+        assert_eq!(file.len(), 5);
+    }
+
+    #[rocket::async_test]
+    async fn enforces_size_and_time_limits() {
+        let addr = stand_in().await;
+        let trace = TraceContext::start("test");
+        let result = download(&format!("http://{}/cat.txt", addr), 4, &conf(true), &trace).await;
+        assert_eq!(error_message(result), "The file is larger than 4 bytes");
+        let result = download(&format!("http://{}/large", addr), 32, &conf(true), &trace).await;
+        assert_eq!(error_message(result), "The file is larger than 32 bytes");
+        let result = download(&format!("http://{}/slow", addr), 64, &conf(true), &trace).await;
+        assert_eq!(
+            error_message(result),
+            "Timed out while downloading the file"
+        );
+    }
+
+    #[rocket::async_test]
+    async fn refuses_private_hosts_and_bad_redirects() {
+        let addr = stand_in().await;
+        let trace = TraceContext::start("test");
+        for url in [
+            format!("http://{}/cat.txt", addr),
+            format!("http://localhost:{}/cat.txt", addr.port()),
+            format!("http://[::ffff:127.0.0.1]:{}/cat.txt", addr.port()),
+        ] {
+            let result = download(&url, 64, &conf(false), &trace).await;
+            assert_eq!(
+                error_message(result),
+                "The URL points to a disallowed address"
+            );
+        }
+        let result = download(&format!("http://{}/file", addr), 64, &conf(true), &trace).await;
+        assert_eq!(
+            error_message(result),
+            "Only HTTP and HTTPS URLs are supported"
+        );
+        let result = download(&format!("http://{}/loop", addr), 64, &conf(true), &trace).await;
+        assert_eq!(error_message(result), "The URL redirected too many times");
+    }
+
+    #[test]
+    fn classifies_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fc00::1",
+            "fe80::1",
+            "::ffff:10.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "::",
+            "2002:7f00:1::",
+            "2002:a9fe:a9fe::1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "1.1.1.1",
+            "93.184.216.34",
+            "2606:4700::1111",
+            "::ffff:1.1.1.1",
+            "::1.1.1.1",
+            "2002:101:101::1",
+        ] {
+            assert!(is_public(ip.parse::<IpAddr>().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::{io::Cursor, sync::Arc};
+
+use rocket::{
+    http::ContentType,
+    response::{self, Responder},
+    Request,
+};
+use tokio::fs::File;
+
+use crate::{compression::Encoding, security::ServedBucket};
+
+/// Builds a `Content-Disposition` header for a file, file names can be anything a client sent so
+/// quotes, backslashes and control characters are replaced in the quoted `filename` while names
+/// which aren't plain ASCII are sent as is in a percent encoded `filename*`
+pub fn content_disposition(disposition: &str, name: &str) -> String {
+    let fallback: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii() && !c.is_ascii_control() => c,
+            _ => '_',
+        })
+        .collect();
+    if fallback == name {
+        return format!("{}; filename=\"{}\"", disposition, name);
+    }
+    let encoded: String = name
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => (b as char).to_string(),
+            b => format!("%{:02X}", b),
+        })
+        .collect();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition, fallback, encoded
+    )
+}
+
+/// The body of a file response
+#[derive(Debug)]
+pub enum FileBody {
+    File(File),
+    Bytes(Arc<[u8]>),
+}
+
+/// A file served from storage or memory, possibly encoded with one of the encodings the client
+/// accepts
+#[derive(Debug)]
+pub struct FileResponse {
+    pub body: FileBody,
+    /// The bucket the file is served from, `static` for static files
+    pub bucket: String,
+    pub disposition: String,
+    pub content_type: ContentType,
+    pub encoding: Option<Encoding>,
+    /// Whether the response varies with the `Accept-Encoding` header of the request
+    pub vary_encoding: bool,
+    /// The `Digest` header of the file, only set when the body is sent as it was stored
+    pub digest: Option<String>,
+}
+
+impl<'r> Responder<'r, 'static> for FileResponse {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        request.local_cache(|| ServedBucket(Some(self.bucket.clone())));
+        let mut response = match self.body {
+            FileBody::File(file) => file.respond_to(request)?,
+            FileBody::Bytes(data) => {
+                let mut response = response::Response::new();
+                response.set_sized_body(data.len(), Cursor::new(data));
+                response
+            }
+        };
+        response.set_header(self.content_type);
+        response.set_raw_header("Content-Disposition", self.disposition);
+        if let Some(encoding) = self.encoding {
+            response.set_raw_header("Content-Encoding", encoding.as_str());
+        }
+        if self.vary_encoding {
+            response.set_raw_header("Vary", "Accept-Encoding");
+        }
+        if let Some(digest) = self.digest {
+            response.set_raw_header("Digest", digest);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::content_disposition;
+
+    #[test]
+    fn plain_names_are_quoted_as_is() {
+        assert_eq!(
+            content_disposition("inline", "cat.png"),
+            "inline; filename=\"cat.png\""
+        );
+    }
+
+    #[test]
+    fn header_breaking_characters_are_replaced() {
+        assert_eq!(
+            content_disposition("attachment", "a\"b\r\nSet-Cookie: x.txt"),
+            "attachment; filename=\"a_b__Set-Cookie: x.txt\"; filename*=UTF-8''a%22b%0D%0ASet-Cookie%3A%20x.txt"
+        );
+    }
+
+    #[test]
+    fn non_ascii_names_are_percent_encoded() {
+        assert_eq!(
+            content_disposition("inline", "café.png"),
+            "inline; filename=\"caf_.png\"; filename*=UTF-8''caf%C3%A9.png"
+        );
+    }
+}
@@ -0,0 +1,38 @@
+use rocket::{http::Status, State};
+use rocket_db_pools::Connection;
+
+use crate::{
+    admin::Admin, error::EffisError, file_cache::FileCache, metadata_cache::MetadataCache, Cache,
+    BUCKETS,
+};
+
+/// Purges a file from every cache, used when a file gets removed or moderated
+#[delete("/cache/<bucket>/<id>")]
+pub async fn purge_file(
+    bucket: &str,
+    id: u128,
+    _admin: Admin,
+    mut cache: Connection<Cache>,
+    file_cache: &State<FileCache>,
+) -> Result<Status, EffisError> {
+    if !BUCKETS.contains(&bucket) {
+        return Err(EffisError::Validation {
+            field_name: "bucket".to_string(),
+            error: "Unknown bucket".to_string(),
+        });
+    }
+    file_cache.invalidate(bucket, id);
+    MetadataCache::invalidate(bucket, id, &mut *cache)
+        .await
+        .map_err(|e| {
+            log::error!(
+                "Failed to purge cached metadata of {} from {}: {}",
+                id,
+                bucket,
+                e
+            );
+            EffisError::Server("Failed to purge file".to_string())
+        })?;
+    log::info!("Purged file {} from {} caches", id, bucket);
+    Ok(Status::NoContent)
+}
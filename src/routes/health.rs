@@ -0,0 +1,129 @@
+use std::{collections::HashSet, io, path::Path};
+
+use rocket::{http::Status, serde::json::Json, State};
+use rocket_db_pools::deadpool_redis::redis;
+use serde::Serialize;
+use sqlx::migrate::Migrate;
+use tokio::fs;
+
+use crate::{storage, Cache, BUCKETS, DB, MIGRATOR};
+
+#[derive(Debug, Serialize)]
+pub struct Health {
+    status: &'static str,
+}
+
+/// The outcome of a single readiness check
+#[derive(Debug, Serialize)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl<E: ToString> From<Result<(), E>> for Check {
+    fn from(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Self {
+                ok: true,
+                error: None,
+            },
+            Err(err) => Self {
+                ok: false,
+                error: Some(err.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    ready: bool,
+    database: Check,
+    cache: Check,
+    storage: Check,
+    migrations: Check,
+}
+
+async fn check_database(db: &DB) -> Result<(), String> {
+    let mut conn = db.acquire().await.map_err(|e| e.to_string())?;
+    sqlx::query("SELECT 1")
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn check_cache(cache: &Cache) -> Result<(), String> {
+    let mut conn = cache.get().await.map_err(|e| e.to_string())?;
+    redis::cmd("PING")
+        .query_async::<_, String>(&mut conn)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+async fn probe_directory(path: &Path) -> io::Result<()> {
+    let path = path.join(".ready");
+    fs::write(&path, b"").await?;
+    fs::remove_file(&path).await
+}
+
+async fn check_storage() -> Result<(), String> {
+    for dir in BUCKETS.iter().chain(&["static"]) {
+        let path = storage::bucket_path(dir);
+        probe_directory(&path)
+            .await
+            .map_err(|e| format!("{} is not writable: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+async fn check_migrations(db: &DB) -> Result<(), String> {
+    let mut conn = db.acquire().await.map_err(|e| e.to_string())?;
+    let applied: HashSet<i64> = conn
+        .list_applied_migrations()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|m| m.version)
+        .collect();
+    match MIGRATOR.iter().find(|m| !applied.contains(&m.version)) {
+        Some(migration) => Err(format!(
+            "Migration {} ({}) has not been applied",
+            migration.version, migration.description
+        )),
+        None => Ok(()),
+    }
+}
+
+#[get("/health")]
+pub async fn health() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+#[get("/ready")]
+pub async fn ready(db: &State<DB>, cache: &State<Cache>) -> (Status, Json<Readiness>) {
+    let database = Check::from(check_database(db).await);
+    let cache = Check::from(check_cache(cache).await);
+    let storage = Check::from(check_storage().await);
+    let migrations = Check::from(check_migrations(db).await);
+    let ready = database.ok && cache.ok && storage.ok && migrations.ok;
+    if !ready {
+        log::warn!("Readiness check failed");
+    }
+    (
+        if ready {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        Json(Readiness {
+            ready,
+            database,
+            cache,
+            storage,
+            migrations,
+        }),
+    )
+}
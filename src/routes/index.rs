@@ -1,99 +1,251 @@
+use std::collections::HashMap;
+
 use rocket::{form::Form, serde::json::Json, State};
 use rocket_db_pools::Connection;
-use todel::{
-    http::ClientIP,
-    ids::IDGenerator,
-    models::{ErrorResponseData, FetchResponse, File, FileData, FileUpload, ValidationError},
-    Conf,
-};
+use todel::{ids::IDGenerator, Conf};
 use tokio::sync::Mutex;
 
 use crate::{
-    ratelimit::{RatelimitedRouteResponse, Ratelimiter},
-    Cache, DB,
+    checksum::ChecksumHeader,
+    error::EffisError,
+    file_cache::FileCache,
+    metadata_cache::MetadataCache,
+    metrics::UPLOADED_BYTES,
+    models::{BatchFetch, BatchFileData, HashedFileData},
+    ratelimit::{RatelimitCache, RatelimitedRouteResponse, Ratelimiter},
+    remote::{self, RemoteConf, UrlUpload},
+    response::FileResponse,
+    telemetry::TraceContext,
+    tier::Identifier,
+    upload::{create_file, create_files, FileUpload, MultiFileUpload},
+    DB,
 };
 
 #[post("/", data = "<upload>")]
-pub async fn upload<'a>(
-    upload: Form<FileUpload<'a>>,
-    ip: ClientIP,
-    mut cache: Connection<Cache>,
+#[allow(clippy::too_many_arguments)]
+pub async fn upload(
+    upload: Form<FileUpload>,
+    checksum: ChecksumHeader,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
     mut db: Connection<DB>,
     conf: &State<Conf>,
     gen: &State<Mutex<IDGenerator>>,
-) -> RatelimitedRouteResponse<Json<FileData>> {
-    let mut ratelimiter = Ratelimiter::new("attachments", "attachments", ip, conf.inner());
-    ratelimiter
-        .process_ratelimit(upload.file.len(), &mut cache)
+) -> RatelimitedRouteResponse<Json<HashedFileData>> {
+    let mut ratelimiter = Ratelimiter::new("attachments", "attachments", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(upload.file.len(), &mut cache),
+        )
         .await?;
-    if upload.file.len() == 0 {
-        Err(ratelimiter
-            .wrap_response::<_, ()>(
-                ValidationError {
-                    field_name: "file".to_string(),
-                    error: "You cannot upload empty files".to_string(),
-                }
-                .to_error_response(),
-            )
-            .unwrap())?;
+    if upload.file.is_empty() {
+        return Err(ratelimiter.wrap_error(EffisError::Validation {
+            field_name: "file".to_string(),
+            error: "You cannot upload empty files".to_string(),
+        }));
     }
+    let size = upload.file.len();
     let upload = upload.into_inner();
-    let file = File::create(
-        upload.file,
-        "attachments".to_string(),
-        gen.inner(),
-        &mut db,
-        upload.spoiler,
-    )
-    .await
-    .map_err(|e| ratelimiter.wrap_response::<_, ()>(e).unwrap())?;
-    ratelimiter.wrap_response(Json(file))
+    let file = ratelimiter.check(
+        create_file(
+            upload.file,
+            "attachments",
+            upload.spoiler,
+            upload.checksum.as_deref().or(checksum.0.as_deref()),
+            gen.inner(),
+            &mut db,
+            &trace,
+        )
+        .await,
+    )?;
+    UPLOADED_BYTES
+        .with_label_values(&["attachments"])
+        .inc_by(size);
+    Ok(ratelimiter.wrap_response(Json(file)))
 }
 
 #[get("/<id>")]
-pub async fn fetch<'a>(
+pub async fn fetch(
     id: u128,
-    ip: ClientIP,
-    mut cache: Connection<Cache>,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
     mut db: Connection<DB>,
     conf: &State<Conf>,
-) -> RatelimitedRouteResponse<FetchResponse<'a>> {
-    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", ip, conf.inner());
-    ratelimiter.process_ratelimit(0, &mut cache).await?;
-    let file = File::fetch_file(id, "attachments", &mut db)
-        .await
-        .map_err(|e| ratelimiter.wrap_response::<_, ()>(e).unwrap())?;
-    ratelimiter.wrap_response(file)
+    file_cache: &State<FileCache>,
+) -> RatelimitedRouteResponse<FileResponse> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    let file = ratelimiter.check(
+        file_cache
+            .fetch(id, "attachments", "inline", &mut db, &trace)
+            .await,
+    )?;
+    Ok(ratelimiter.wrap_response(file))
 }
 
 #[get("/<id>/download", rank = 2)]
-pub async fn fetch_download<'a>(
+pub async fn fetch_download(
     id: u128,
-    ip: ClientIP,
-    mut cache: Connection<Cache>,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
     mut db: Connection<DB>,
     conf: &State<Conf>,
-) -> RatelimitedRouteResponse<FetchResponse<'a>> {
-    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", ip, conf.inner());
-    ratelimiter.process_ratelimit(0, &mut cache).await?;
-    let file = File::fetch_file_download(id, "attachments", &mut db)
-        .await
-        .map_err(|e| ratelimiter.wrap_response::<_, ()>(e).unwrap())?;
-    ratelimiter.wrap_response(file)
+    file_cache: &State<FileCache>,
+) -> RatelimitedRouteResponse<FileResponse> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    let file = ratelimiter.check(
+        file_cache
+            .fetch(id, "attachments", "attachment", &mut db, &trace)
+            .await,
+    )?;
+    Ok(ratelimiter.wrap_response(file))
 }
 
 #[get("/<id>/data", rank = 2)]
 pub async fn fetch_data<'a>(
     id: u128,
-    ip: ClientIP,
-    mut cache: Connection<Cache>,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
+    mut db: Connection<DB>,
+    conf: &State<Conf>,
+    metadata_cache: &State<MetadataCache>,
+) -> RatelimitedRouteResponse<Json<HashedFileData>> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    let file = ratelimiter.check(
+        metadata_cache
+            .fetch_file_data(id, "attachments", &mut db, cache.connection(), &trace)
+            .await,
+    )?;
+    Ok(ratelimiter.wrap_response(Json(file)))
+}
+
+#[post("/data", data = "<batch>")]
+pub async fn fetch_data_batch(
+    batch: Json<BatchFetch>,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
+    mut db: Connection<DB>,
+    conf: &State<Conf>,
+    metadata_cache: &State<MetadataCache>,
+) -> RatelimitedRouteResponse<Json<HashMap<u128, BatchFileData>>> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit_weighted(0, batch.weight(), &mut cache),
+        )
+        .await?;
+    let ids = ratelimiter.check(batch.validate())?;
+    let files = ratelimiter.check(
+        metadata_cache
+            .fetch_file_data_batch(&ids, "attachments", &mut db, cache.connection(), &trace)
+            .await,
+    )?;
+    Ok(ratelimiter.wrap_response(Json(BatchFileData::from_files(&ids, files))))
+}
+
+#[post("/multi", data = "<upload>")]
+pub async fn upload_multiple(
+    upload: MultiFileUpload,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
+    mut db: Connection<DB>,
+    conf: &State<Conf>,
+    gen: &State<Mutex<IDGenerator>>,
+) -> RatelimitedRouteResponse<Json<Vec<HashedFileData>>> {
+    let mut ratelimiter = Ratelimiter::new("attachments", "attachments", &identifier, conf.inner());
+    let size = upload.size();
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit_weighted(
+                size,
+                upload.files.len().max(1) as u32,
+                &mut cache,
+            ),
+        )
+        .await?;
+    ratelimiter.check(upload.validate())?;
+    let files = ratelimiter
+        .check(create_files(upload, "attachments", gen.inner(), &mut db, &trace).await)?;
+    UPLOADED_BYTES
+        .with_label_values(&["attachments"])
+        .inc_by(size);
+    Ok(ratelimiter.wrap_response(Json(files)))
+}
+
+#[post("/url", data = "<upload>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_url(
+    upload: Json<UrlUpload>,
+    checksum: ChecksumHeader,
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
     mut db: Connection<DB>,
     conf: &State<Conf>,
-) -> RatelimitedRouteResponse<Json<FileData>> {
-    let mut ratelimiter = Ratelimiter::new("fetch_file", "attachments", ip, conf.inner());
-    ratelimiter.process_ratelimit(0, &mut cache).await?;
-    let file = File::fetch_file_data(id, "attachments", &mut db)
-        .await
-        .map_err(|e| ratelimiter.wrap_response::<_, ()>(e).unwrap())?;
-    ratelimiter.wrap_response(Json(file))
+    gen: &State<Mutex<IDGenerator>>,
+    remote_conf: &State<RemoteConf>,
+) -> RatelimitedRouteResponse<Json<HashedFileData>> {
+    let mut ratelimiter = Ratelimiter::new("attachments", "attachments", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    // The size is only known once the file is downloaded, so the download is capped by what's
+    // left in the bucket, all of which is reserved up front so concurrent downloads can't go over
+    // it, and whatever the download didn't use is refunded afterwards
+    let max_size = conf
+        .effis
+        .attachment_file_size
+        .min(ratelimiter.bytes_left());
+    ratelimiter.record_bytes(max_size, &mut cache).await;
+    let remote = remote::download(&upload.url, max_size, remote_conf.inner(), &trace).await;
+    let size = remote.as_ref().map(|f| f.len()).unwrap_or(0);
+    ratelimiter
+        .refund_bytes(max_size.saturating_sub(size), &mut cache)
+        .await;
+    let remote = ratelimiter.check(remote)?;
+    let file = ratelimiter.check(
+        create_file(
+            remote,
+            "attachments",
+            upload.spoiler,
+            upload.checksum.as_deref().or(checksum.0.as_deref()),
+            gen.inner(),
+            &mut db,
+            &trace,
+        )
+        .await,
+    )?;
+    UPLOADED_BYTES
+        .with_label_values(&["attachments"])
+        .inc_by(size);
+    Ok(ratelimiter.wrap_response(Json(file)))
 }
@@ -1,20 +1,39 @@
 mod buckets;
+mod cache;
+mod health;
 mod index;
+mod ratelimits;
 mod static_routes;
 
 use rocket::Route;
 
 pub fn routes() -> Vec<Route> {
     routes![
+        health::health,
+        health::ready,
+        cache::purge_file,
+        ratelimits::get_ratelimits,
+        ratelimits::reset_ratelimits,
+        ratelimits::restrict,
+        ratelimits::unrestrict,
         static_routes::fetch_static_file,
         static_routes::download_static_file,
+        static_routes::list_static_files,
+        static_routes::upload_static_file,
+        static_routes::delete_static_file,
         index::upload,
+        index::upload_multiple,
+        index::upload_url,
         index::fetch,
         index::fetch_download,
         index::fetch_data,
+        index::fetch_data_batch,
         buckets::upload,
+        buckets::upload_multiple,
+        buckets::upload_url,
         buckets::fetch,
         buckets::fetch_download,
         buckets::fetch_data,
+        buckets::fetch_data_batch,
     ]
 }
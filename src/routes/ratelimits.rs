@@ -0,0 +1,100 @@
+use std::net::IpAddr;
+
+use rocket::{http::Status, serde::json::Json, State};
+use rocket_db_pools::Connection;
+
+use crate::{
+    admin::Admin,
+    client_ip::ProxyConf,
+    error::EffisError,
+    ratelimit::{self, IdentifierRatelimits, NewRestriction, RatelimitFallback, Restriction},
+    Cache,
+};
+
+/// Turns the identifier of a route into the one ratelimits are keyed on, IPs get grouped the
+/// same way the IPs of requests are while anything else, like the `key-<hash>` identifiers of
+/// API keys, is used as is
+fn ratelimit_identifier(identifier: &str, proxy_conf: &ProxyConf) -> String {
+    match identifier.parse::<IpAddr>() {
+        Ok(ip) => proxy_conf.client(ip).to_string(),
+        Err(_) => identifier.to_string(),
+    }
+}
+
+fn cache_error(identifier: &str, err: impl std::fmt::Display) -> EffisError {
+    log::error!("Failed to access the ratelimits of {}: {}", identifier, err);
+    EffisError::Server("Failed to access ratelimits".to_string())
+}
+
+/// Gets every ratelimit bucket of an identifier along with its restriction
+#[get("/ratelimits/<identifier>")]
+pub async fn get_ratelimits(
+    identifier: &str,
+    _admin: Admin,
+    mut cache: Connection<Cache>,
+    proxy_conf: &State<ProxyConf>,
+) -> Result<Json<IdentifierRatelimits>, EffisError> {
+    let identifier = ratelimit_identifier(identifier, proxy_conf);
+    let ratelimits = ratelimit::fetch_ratelimits(&identifier, &mut cache)
+        .await
+        .map_err(|e| cache_error(&identifier, e))?;
+    Ok(Json(ratelimits))
+}
+
+/// Resets every ratelimit bucket of an identifier, leaving its restriction in place
+#[delete("/ratelimits/<identifier>")]
+pub async fn reset_ratelimits(
+    identifier: &str,
+    _admin: Admin,
+    mut cache: Connection<Cache>,
+    proxy_conf: &State<ProxyConf>,
+) -> Result<Status, EffisError> {
+    let identifier = ratelimit_identifier(identifier, proxy_conf);
+    let reset = ratelimit::reset_ratelimits(&identifier, &mut cache)
+        .await
+        .map_err(|e| cache_error(&identifier, e))?;
+    log::info!("Reset {} ratelimit buckets of {}", reset, identifier);
+    Ok(Status::NoContent)
+}
+
+/// Temporarily bans or throttles an identifier, replacing its current restriction
+///
+/// Identifiers in an exempt tier bypass ratelimits entirely, restrictions included
+#[put("/ratelimits/<identifier>/restriction", data = "<restriction>")]
+pub async fn restrict(
+    identifier: &str,
+    restriction: Json<NewRestriction>,
+    _admin: Admin,
+    mut cache: Connection<Cache>,
+    proxy_conf: &State<ProxyConf>,
+    fallback: &State<RatelimitFallback>,
+) -> Result<Json<Restriction>, EffisError> {
+    let identifier = ratelimit_identifier(identifier, proxy_conf);
+    let now = ratelimit::now_millis();
+    let restriction = restriction.into_inner().into_restriction(now)?;
+    ratelimit::set_restriction(&identifier, &restriction, now, &mut cache, fallback)
+        .await
+        .map_err(|e| cache_error(&identifier, e))?;
+    log::info!("Restricted {} with {:?}", identifier, restriction);
+    Ok(Json(restriction))
+}
+
+/// Lifts the restriction of an identifier
+#[delete("/ratelimits/<identifier>/restriction")]
+pub async fn unrestrict(
+    identifier: &str,
+    _admin: Admin,
+    mut cache: Connection<Cache>,
+    proxy_conf: &State<ProxyConf>,
+    fallback: &State<RatelimitFallback>,
+) -> Result<Status, EffisError> {
+    let identifier = ratelimit_identifier(identifier, proxy_conf);
+    let removed = ratelimit::remove_restriction(&identifier, &mut cache, fallback)
+        .await
+        .map_err(|e| cache_error(&identifier, e))?;
+    if !removed {
+        return Err(EffisError::NotFound);
+    }
+    log::info!("Lifted the restriction of {}", identifier);
+    Ok(Status::NoContent)
+}
@@ -1,154 +1,436 @@
-use std::{io::ErrorKind, path::Path};
+use std::{
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use crate::{
-    ratelimit::{RatelimitedRouteResponse, Ratelimiter},
-    Cache,
+    admin::Admin,
+    checksum,
+    compression::{self, AcceptEncoding, CompressionCache, Encoding},
+    error::EffisError,
+    models::StaticFile,
+    ratelimit::{RatelimitCache, RatelimitedRouteResponse, Ratelimiter},
+    response::{content_disposition, FileBody, FileResponse},
+    storage::{self, StreamedFile},
+    telemetry::TraceContext,
+    tier::Identifier,
+    DB,
 };
 use rocket::{
-    http::{ContentType, Header},
+    form::Form,
+    http::{ContentType, Status},
+    serde::json::Json,
     State,
 };
 use rocket_db_pools::Connection;
-use todel::{
-    http::ClientIP,
-    models::{
-        ErrorResponse, ErrorResponseData, FetchResponse, NotFoundError, ServerError,
-        ValidationError,
-    },
-    Conf,
+use sqlx::{pool::PoolConnection, MySql};
+use todel::Conf;
+use tokio::{
+    fs::{self, File},
+    io::AsyncReadExt,
 };
-use tokio::fs::File;
 
-#[get("/static/<name>")]
-pub async fn fetch_static_file<'a>(
-    name: &'a str,
-    ip: ClientIP,
-    mut cache: Connection<Cache>,
-    conf: &State<Conf>,
-) -> RatelimitedRouteResponse<FetchResponse<'a>> {
-    let mut ratelimiter = Ratelimiter::new("fetch_file", "static", ip, conf.inner());
-    ratelimiter.process_ratelimit(0, &mut cache).await?;
-    let path = Path::new(name).file_name().map(Path::new).ok_or_else(|| {
-        ratelimiter
-            .wrap_response::<_, ()>(
-                ValidationError {
-                    field_name: "name".to_string(),
-                    error: "Could not find a valid file name".to_string(),
-                }
-                .to_error_response(),
-            )
-            .unwrap()
-    })?;
-    let extension = path.extension();
-    let content_type = match extension {
-        Some(extension) => ContentType::from_extension(extension.to_str().ok_or_else(|| {
-            ratelimiter
-                .wrap_response::<_, ()>(
-                    ValidationError {
-                        field_name: "name".to_string(),
-                        error: "Invalid file extension".to_string(),
-                    }
-                    .to_error_response(),
-                )
-                .unwrap()
-        })?),
-        None => None,
+fn open_error(name: &str, err: io::Error) -> EffisError {
+    if err.kind() == ErrorKind::NotFound {
+        EffisError::NotFound
+    } else {
+        log::error!("Failed to open static file {}: {}", name, err);
+        EffisError::Server("Failed to fetch file".to_string())
+    }
+}
+
+/// The path of the sibling of a static file which is precompressed in an encoding
+fn precompressed_path(path: &Path, encoding: Encoding) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(encoding.extension());
+    path.with_file_name(name)
+}
+
+/// Opens the precompressed sibling of a static file, unless it's older than the file itself
+/// since it was then compressed from a version of the file which has been replaced
+async fn open_precompressed(path: &Path, encoding: Encoding) -> io::Result<Option<File>> {
+    let file = match File::open(precompressed_path(path, encoding)).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
     };
-    let file = File::open(Path::new("./files/static").join(path))
+    let original = match fs::metadata(path).await {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if file.metadata().await?.modified()? < original.modified()? {
+        return Ok(None);
+    }
+    Ok(Some(file))
+}
+
+/// Removes the precompressed siblings of a static file which is being replaced or deleted
+async fn remove_precompressed(path: &Path) -> io::Result<()> {
+    for encoding in [Encoding::Brotli, Encoding::Gzip] {
+        match fs::remove_file(precompressed_path(path, encoding)).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Opens a static file with the content type of its manifest entry, responding with the
+/// provided content disposition
+async fn open_static_file(
+    name: &str,
+    disposition: &str,
+    accept: AcceptEncoding,
+    compression_cache: &CompressionCache,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<FileResponse, EffisError> {
+    let name = Path::new(name)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| EffisError::Validation {
+            field_name: "name".to_string(),
+            error: "Could not find a valid file name".to_string(),
+        })?;
+    let path = storage::bucket_path("static").join(name);
+    let manifest = trace
+        .instrument("StaticFile::get", StaticFile::get(name, db))
         .await
-        .map_err(|e| {
-            if e.kind() == ErrorKind::NotFound {
-                ratelimiter
-                    .wrap_response::<_, ()>(NotFoundError.to_error_response())
-                    .unwrap()
-            } else {
-                ratelimiter
-                    .wrap_response::<_, ()>(
-                        ServerError {
-                            error: "Failed to upload file".to_string(),
-                        }
-                        .to_error_response(),
-                    )
-                    .unwrap()
+        .map_err(database_error)?;
+    let content_type = match &manifest {
+        Some(file) => {
+            ContentType::parse_flexible(&file.content_type).unwrap_or(ContentType::Binary)
+        }
+        // files which were put in the static directory by hand aren't in the manifest
+        None => path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Any),
+    };
+    let compressible = compression::is_compressible(&content_type);
+    let encoding = accept.0.filter(|_| compressible);
+    let mut response = FileResponse {
+        body: FileBody::Bytes(Vec::new().into()),
+        bucket: "static".to_string(),
+        disposition: content_disposition(disposition, name),
+        content_type,
+        encoding: None,
+        vary_encoding: compressible,
+        digest: None,
+    };
+
+    if let Some(encoding) = encoding {
+        match trace
+            .instrument("fs::open", open_precompressed(&path, encoding))
+            .await
+        {
+            Ok(Some(file)) => {
+                log::info!("Fetched precompressed static file {}", name);
+                response.body = FileBody::File(file);
+                response.encoding = Some(encoding);
+                return Ok(response);
             }
-        })?;
+            Ok(None) => {}
+            Err(err) => log::warn!("Failed to open precompressed static file {}: {}", name, err),
+        }
+    }
+
+    let mut file = trace
+        .instrument("fs::open", File::open(&path))
+        .await
+        .map_err(|e| open_error(name, e))?;
+    if let Some(encoding) = encoding {
+        let metadata = file.metadata().await.map_err(|e| open_error(name, e))?;
+        if metadata.len() <= compression::MAX_COMPRESSED_SIZE {
+            // files which were put in the static directory by hand have no hash, their
+            // modification time tells their versions apart instead
+            let version = match &manifest {
+                Some(file) => file.hash.clone(),
+                None => format!("{:?}-{}", metadata.modified().ok(), metadata.len()),
+            };
+            let data = match compression_cache.get(name, encoding, &version) {
+                Some(data) => {
+                    log::info!("Fetched static file {} compressed from memory", name);
+                    data
+                }
+                None => {
+                    let mut data = Vec::with_capacity(metadata.len() as usize);
+                    trace
+                        .instrument("fs::read", file.read_to_end(&mut data))
+                        .await
+                        .map_err(|e| open_error(name, e))?;
+                    let data: Arc<[u8]> = trace
+                        .instrument("compress", compression::compress(data, encoding))
+                        .await
+                        .map_err(|e| {
+                            log::error!("Failed to compress static file {}: {}", name, e);
+                            EffisError::Server("Failed to fetch file".to_string())
+                        })?
+                        .into();
+                    compression_cache.insert(name, encoding, version, Arc::clone(&data));
+                    log::info!("Fetched static file {} compressed on the fly", name);
+                    data
+                }
+            };
+            response.body = FileBody::Bytes(data);
+            response.encoding = Some(encoding);
+            return Ok(response);
+        }
+    }
+
     log::info!("Fetched static file {}", name);
-    ratelimiter.wrap_response(FetchResponse {
-        file,
-        disposition: Header::new(
-            "Content-Disposition",
-            format!(
-                "inline; filename=\"{}\"",
-                path.file_name().unwrap().to_str().unwrap()
-            ),
-        ),
-        content_type: content_type.unwrap_or(ContentType::Any),
-    })
+    response.body = FileBody::File(file);
+    response.digest = manifest.and_then(|f| checksum::digest_header(&f.hash));
+    Ok(response)
+}
+
+#[get("/static/<name>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn fetch_static_file(
+    name: &str,
+    identifier: Identifier,
+    accept: AcceptEncoding,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
+    mut db: Connection<DB>,
+    conf: &State<Conf>,
+    compression_cache: &State<CompressionCache>,
+) -> RatelimitedRouteResponse<FileResponse> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "static", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    let file = ratelimiter.check(
+        open_static_file(name, "inline", accept, compression_cache, &mut db, &trace).await,
+    )?;
+    Ok(ratelimiter.wrap_response(file))
 }
 
 #[get("/static/<name>/download")]
-pub async fn download_static_file<'a>(
-    name: &'a str,
-    ip: ClientIP,
-    mut cache: Connection<Cache>,
+#[allow(clippy::too_many_arguments)]
+pub async fn download_static_file(
+    name: &str,
+    identifier: Identifier,
+    accept: AcceptEncoding,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
+    mut db: Connection<DB>,
     conf: &State<Conf>,
-) -> RatelimitedRouteResponse<Result<FetchResponse<'a>, ErrorResponse>> {
-    let mut ratelimiter = Ratelimiter::new("fetch_file", "static", ip, conf.inner());
-    ratelimiter.process_ratelimit(0, &mut cache).await?;
-    let path = Path::new(name).file_name().map(Path::new).ok_or_else(|| {
-        ratelimiter
-            .wrap_response::<_, ()>(
-                ValidationError {
-                    field_name: "name".to_string(),
-                    error: "Could not find a valid file name".to_string(),
-                }
-                .to_error_response(),
-            )
-            .unwrap()
-    })?;
-    let extension = path.extension();
-    let content_type = match extension {
-        Some(extension) => ContentType::from_extension(extension.to_str().ok_or_else(|| {
-            ratelimiter
-                .wrap_response::<_, ()>(
-                    ValidationError {
-                        field_name: "name".to_string(),
-                        error: "Invalid file extension".to_string(),
-                    }
-                    .to_error_response(),
-                )
-                .unwrap()
-        })?),
-        None => None,
+    compression_cache: &State<CompressionCache>,
+) -> RatelimitedRouteResponse<FileResponse> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "static", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    let file = ratelimiter.check(
+        open_static_file(
+            name,
+            "attachment",
+            accept,
+            compression_cache,
+            &mut db,
+            &trace,
+        )
+        .await,
+    )?;
+    Ok(ratelimiter.wrap_response(file))
+}
+
+/// A static file, streamed into the storage directory while it's hashed so that it can be
+/// renamed over the old file at once
+#[derive(Debug, FromForm)]
+pub struct StaticFileUpload {
+    pub file: StreamedFile,
+}
+
+/// Checks that a static file name doesn't point anywhere outside of the static directory
+fn validate_name(name: &str) -> Result<(), EffisError> {
+    if name.len() > 255
+        || name.starts_with('.')
+        || Path::new(name).file_name().and_then(|n| n.to_str()) != Some(name)
+    {
+        return Err(EffisError::Validation {
+            field_name: "name".to_string(),
+            error: "Invalid file name".to_string(),
+        });
+    }
+    Ok(())
+}
+
+fn database_error(err: sqlx::Error) -> EffisError {
+    log::error!("Failed to query static file manifest: {}", err);
+    EffisError::Server("Failed to query static file manifest".to_string())
+}
+
+#[get("/static")]
+pub async fn list_static_files(
+    identifier: Identifier,
+    trace: TraceContext,
+    mut cache: RatelimitCache<'_>,
+    mut db: Connection<DB>,
+    conf: &State<Conf>,
+) -> RatelimitedRouteResponse<Json<Vec<StaticFile>>> {
+    let mut ratelimiter = Ratelimiter::new("fetch_file", "static", &identifier, conf.inner());
+    trace
+        .instrument(
+            "process_ratelimit",
+            ratelimiter.process_ratelimit(0, &mut cache),
+        )
+        .await?;
+    let files = ratelimiter.check(
+        trace
+            .instrument("StaticFile::list", StaticFile::list(&mut db))
+            .await
+            .map_err(database_error),
+    )?;
+    Ok(ratelimiter.wrap_response(Json(files)))
+}
+
+#[put("/static/<name>", data = "<upload>")]
+pub async fn upload_static_file(
+    name: &str,
+    upload: Form<StaticFileUpload>,
+    _admin: Admin,
+    trace: TraceContext,
+    mut db: Connection<DB>,
+) -> Result<Json<StaticFile>, EffisError> {
+    validate_name(name)?;
+    let upload = upload.into_inner();
+    if upload.file.is_empty() {
+        return Err(EffisError::Validation {
+            field_name: "file".to_string(),
+            error: "You cannot upload empty files".to_string(),
+        });
+    }
+
+    let path = storage::bucket_path("static").join(name);
+    let file = StaticFile {
+        name: name.to_string(),
+        size: upload.file.len(),
+        content_type: path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(ContentType::from_extension)
+            .unwrap_or(ContentType::Binary)
+            .to_string(),
+        hash: upload.file.hash().to_string(),
     };
-    let file = File::open(Path::new("./files/static").join(path))
+    // the siblings were compressed from the file being replaced
+    trace
+        .instrument("fs::remove_file", remove_precompressed(&path))
         .await
         .map_err(|e| {
-            if e.kind() == ErrorKind::NotFound {
-                ratelimiter
-                    .wrap_response::<_, ()>(NotFoundError.to_error_response())
-                    .unwrap()
-            } else {
-                ratelimiter
-                    .wrap_response::<_, ()>(
-                        ServerError {
-                            error: "Failed to upload file".to_string(),
-                        }
-                        .to_error_response(),
-                    )
-                    .unwrap()
-            }
+            log::error!("Failed to remove precompressed static file {}: {}", name, e);
+            EffisError::Server("Failed to upload file".to_string())
         })?;
-    log::info!("Fetched static file {}", name);
-    ratelimiter.wrap_response(Ok(FetchResponse {
-        file,
-        disposition: Header::new(
-            "Content-Disposition",
-            format!(
-                "attachment; filename=\"{}\"",
-                path.file_name().unwrap().to_str().unwrap()
-            ),
-        ),
-        content_type: content_type.unwrap_or(ContentType::Any),
-    }))
+    trace
+        .instrument("fs::rename", upload.file.persist_to(&path))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to persist static file {}: {}", name, e);
+            EffisError::Server("Failed to upload file".to_string())
+        })?;
+    trace
+        .instrument("StaticFile::save", file.save(&mut db))
+        .await
+        .map_err(database_error)?;
+    log::info!("Uploaded static file {}", name);
+    Ok(Json(file))
+}
+
+#[delete("/static/<name>")]
+pub async fn delete_static_file(
+    name: &str,
+    _admin: Admin,
+    trace: TraceContext,
+    mut db: Connection<DB>,
+) -> Result<Status, EffisError> {
+    validate_name(name)?;
+    let existed = trace
+        .instrument("StaticFile::delete", StaticFile::delete(name, &mut db))
+        .await
+        .map_err(database_error)?;
+    let path = storage::bucket_path("static").join(name);
+    trace
+        .instrument("fs::remove_file", remove_precompressed(&path))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to remove precompressed static file {}: {}", name, e);
+            EffisError::Server("Failed to delete file".to_string())
+        })?;
+    match trace
+        .instrument("fs::remove_file", fs::remove_file(&path))
+        .await
+    {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound && existed => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Err(EffisError::NotFound),
+        Err(e) => {
+            log::error!("Failed to delete static file {}: {}", name, e);
+            return Err(EffisError::Server("Failed to delete file".to_string()));
+        }
+    }
+    log::info!("Deleted static file {}", name);
+    Ok(Status::NoContent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[rocket::async_test]
+    async fn skips_precompressed_files_of_replaced_files() {
+        let dir = std::env::temp_dir().join(format!("effis-static-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let path = dir.join("style.css");
+        fs::write(&path, "old").await.unwrap();
+        fs::write(precompressed_path(&path, Encoding::Gzip), "old gzip")
+            .await
+            .unwrap();
+        assert!(open_precompressed(&path, Encoding::Gzip)
+            .await
+            .unwrap()
+            .is_some());
+        assert!(open_precompressed(&path, Encoding::Brotli)
+            .await
+            .unwrap()
+            .is_none());
+
+        // a file replaced by hand is newer than its siblings
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        fs::write(&path, "new").await.unwrap();
+        assert!(open_precompressed(&path, Encoding::Gzip)
+            .await
+            .unwrap()
+            .is_none());
+
+        fs::write(precompressed_path(&path, Encoding::Brotli), "new brotli")
+            .await
+            .unwrap();
+        remove_precompressed(&path).await.unwrap();
+        assert!(!precompressed_path(&path, Encoding::Gzip).exists());
+        assert!(!precompressed_path(&path, Encoding::Brotli).exists());
+        assert!(path.exists());
+        // deleted files have no siblings to serve either
+        fs::remove_file(&path).await.unwrap();
+        assert!(open_precompressed(&path, Encoding::Gzip)
+            .await
+            .unwrap()
+            .is_none());
+
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
 }
@@ -0,0 +1,88 @@
+use std::env;
+
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Header,
+    Request, Response,
+};
+
+/// The policy of served files, which keeps them from running scripts on the Effis origin when
+/// they're opened directly
+const USER_CONTENT_POLICY: &str =
+    "sandbox; default-src 'none'; img-src 'self' data:; media-src 'self'; style-src 'unsafe-inline'";
+/// The types which browsers can run scripts in when they're rendered inline
+const DANGEROUS_TYPES: [&str; 6] = [
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/xsl",
+];
+
+/// The bucket the file of a response is served from, set by [`FileResponse`]
+///
+/// [`FileResponse`]: crate::response::FileResponse
+#[derive(Debug)]
+pub struct ServedBucket(pub Option<String>);
+
+/// Adds security headers to responses and serves dangerous files as attachments unless their
+/// bucket is listed in the comma separated `EFFIS_INLINE_BUCKETS` environment variable
+#[derive(Debug)]
+pub struct SecurityHeaders {
+    inline_buckets: Vec<String>,
+}
+
+impl SecurityHeaders {
+    pub fn from_env() -> SecurityHeaders {
+        let inline_buckets = env::var("EFFIS_INLINE_BUCKETS")
+            .map(|b| {
+                b.split(',')
+                    .map(|b| b.trim().to_string())
+                    .filter(|b| !b.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+        SecurityHeaders { inline_buckets }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Add security headers to responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new("X-Content-Type-Options", "nosniff"));
+
+        let bucket = match &request.local_cache(|| ServedBucket(None)).0 {
+            Some(bucket) => bucket,
+            None => return,
+        };
+        response.set_header(Header::new("Content-Security-Policy", USER_CONTENT_POLICY));
+
+        let dangerous = response
+            .content_type()
+            .map(|c| {
+                let media_type = format!("{}/{}", c.top(), c.sub()).to_ascii_lowercase();
+                DANGEROUS_TYPES.contains(&media_type.as_str())
+            })
+            .unwrap_or(false);
+        if !dangerous || self.inline_buckets.contains(bucket) {
+            return;
+        }
+        let disposition = response
+            .headers()
+            .get_one("Content-Disposition")
+            .and_then(|d| d.strip_prefix("inline"))
+            .map(|d| format!("attachment{}", d));
+        if let Some(disposition) = disposition {
+            log::debug!("Serving a dangerous file from {} as an attachment", bucket);
+            response.set_raw_header("Content-Disposition", disposition);
+        }
+    }
+}
@@ -0,0 +1,759 @@
+use std::{
+    env,
+    future::Future,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use lazy_static::lazy_static;
+use rocket::{
+    data::Limits,
+    form::{self, error::ErrorKind, DataField, FromFormField},
+};
+use sha2::{Digest, Sha256};
+use sqlx::{pool::PoolConnection, Connection, MySql};
+use todel::ids::IDGenerator;
+use tokio::{
+    fs,
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+    task,
+};
+use uuid::Uuid;
+
+use crate::{
+    error::EffisError,
+    logging,
+    models::{HashedFileData, NewFile, StoredBlob},
+    telemetry::TraceContext,
+};
+
+/// The amount of bytes at the start of a file its type is detected from
+const HEAD_SIZE: usize = 8192;
+/// The maximum length of a file name
+const MAX_NAME_LENGTH: usize = 64;
+
+/// The maximum amount of shard directories blobs can be nested in
+pub const MAX_SHARD_DEPTH: usize = 4;
+
+/// Where blobs are stored, configured with the `EFFIS_STORAGE_ROOT` and
+/// `EFFIS_STORAGE_SHARD_DEPTH` environment variables
+///
+/// Sharded blobs are nested in directories named after the leading bytes of the hash of their
+/// ID, `ab/cd/<id>` with a depth of 2, so busy buckets don't end up with millions of files in a
+/// single directory
+#[derive(Debug, Clone)]
+pub struct StorageLayout {
+    pub root: PathBuf,
+    pub shard_depth: usize,
+}
+
+impl StorageLayout {
+    pub fn from_env() -> Result<StorageLayout, anyhow::Error> {
+        let root = env::var("EFFIS_STORAGE_ROOT")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| PathBuf::from("files"));
+        let shard_depth = match env::var("EFFIS_STORAGE_SHARD_DEPTH") {
+            Ok(depth) => depth
+                .parse::<usize>()
+                .ok()
+                .filter(|d| *d <= MAX_SHARD_DEPTH)
+                .ok_or_else(|| anyhow::anyhow!("Expected a depth of at most {}", MAX_SHARD_DEPTH))
+                .context("Invalid \"EFFIS_STORAGE_SHARD_DEPTH\" environment variable")?,
+            Err(_) => 0,
+        };
+        Ok(StorageLayout { root, shard_depth })
+    }
+
+    /// The path a blob is stored at with a shard depth
+    fn blob_path_at(&self, bucket: &str, file_id: &str, depth: usize) -> PathBuf {
+        let hash = format!("{:x}", Sha256::digest(file_id.as_bytes()));
+        let mut path = self.root.join(bucket);
+        for level in 0..depth {
+            path.push(&hash[level * 2..level * 2 + 2]);
+        }
+        path.join(file_id)
+    }
+}
+
+lazy_static! {
+    static ref LAYOUT: StorageLayout =
+        StorageLayout::from_env().expect("The storage layout is validated on startup");
+}
+
+/// The storage layout of this process
+pub fn layout() -> &'static StorageLayout {
+    &LAYOUT
+}
+
+/// The directory the files of a bucket are stored in
+pub fn bucket_path(bucket: &str) -> PathBuf {
+    LAYOUT.root.join(bucket)
+}
+
+/// The path a blob of a bucket is stored at
+pub fn blob_path(bucket: &str, file_id: &str) -> PathBuf {
+    LAYOUT.blob_path_at(bucket, file_id, LAYOUT.shard_depth)
+}
+
+/// Runs a filesystem operation on a blob, falling back to the paths of other shard depths when it
+/// fails because the blob isn't there, for blobs which weren't migrated to the current layout yet
+pub async fn with_blob<T, F, Fut>(bucket: &str, file_id: &str, op: F) -> io::Result<T>
+where
+    F: Fn(PathBuf) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let path = blob_path(bucket, file_id);
+    let mut result = op(path.clone()).await;
+    for depth in (0..=MAX_SHARD_DEPTH).filter(|d| *d != LAYOUT.shard_depth) {
+        match result {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                result = op(LAYOUT.blob_path_at(bucket, file_id, depth)).await;
+            }
+            result => return result,
+        }
+    }
+    match result {
+        // the blob may have been migrated while it was being looked for
+        Err(err) if err.kind() == io::ErrorKind::NotFound => op(path).await,
+        result => result,
+    }
+}
+
+/// Renames a file into a shard directory, creating it first
+///
+/// Migrations prune shard directories they empty, which can remove the directory between its
+/// creation and the rename, so the rename is retried a few times when that happens
+async fn rename_into(from: &Path, to: &Path) -> io::Result<()> {
+    let mut attempts = 0;
+    loop {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        match fs::rename(from, to).await {
+            Err(err) if err.kind() == io::ErrorKind::NotFound && attempts < 3 => attempts += 1,
+            result => return result,
+        }
+    }
+}
+
+/// Whether a file in a bucket's directory is a blob rather than a leftover upload or probe
+fn is_blob(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| !n.starts_with('.') && !n.ends_with(".upload"))
+        .unwrap_or(false)
+}
+
+/// Lists every blob in a bucket along with the path it's stored at, wherever it's nested
+pub async fn list_blobs(bucket: &str) -> io::Result<Vec<(String, PathBuf)>> {
+    let mut blobs = vec![];
+    let mut dirs = vec![(bucket_path(bucket), 0)];
+    while let Some((dir, depth)) = dirs.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() && depth < MAX_SHARD_DEPTH {
+                dirs.push((path, depth + 1));
+            } else if file_type.is_file() && is_blob(&path) {
+                let file_id = entry.file_name().to_string_lossy().into_owned();
+                blobs.push((file_id, path));
+            }
+        }
+    }
+    Ok(blobs)
+}
+
+/// Moves every blob of a bucket to where the current layout stores it, returning how many were
+/// moved
+///
+/// Blobs are renamed one at a time and lookups fall back to the other layouts, so this can run
+/// while the server is serving files
+pub async fn migrate(bucket: &str) -> io::Result<usize> {
+    let root = bucket_path(bucket);
+    let mut moved = 0;
+    for (file_id, path) in list_blobs(bucket).await? {
+        let target = blob_path(bucket, &file_id);
+        if path == target {
+            continue;
+        }
+        rename_into(&path, &target).await?;
+        moved += 1;
+        if moved % 1000 == 0 {
+            log::info!("Moved {} blobs of {}", moved, bucket);
+        }
+        // clean up the shard directories which were emptied, this fails for ones with files left
+        let mut dir = path.parent();
+        while let Some(current) = dir.filter(|d| *d != root) {
+            if fs::remove_dir(current).await.is_err() {
+                break;
+            }
+            dir = current.parent();
+        }
+    }
+    Ok(moved)
+}
+
+/// A writer which streams a file into the storage directory, hashing it along the way
+#[derive(Debug)]
+pub struct FileWriter {
+    name: String,
+    path: PathBuf,
+    file: fs::File,
+    hasher: Sha256,
+    head: Vec<u8>,
+    len: u64,
+}
+
+impl FileWriter {
+    pub async fn create(name: Option<&str>) -> io::Result<FileWriter> {
+        let name = name
+            .filter(|n| !n.is_empty())
+            .unwrap_or("attachment")
+            .chars()
+            .take(MAX_NAME_LENGTH)
+            .collect();
+        // Uploads are written next to the buckets so they can be renamed into place without
+        // copying them
+        let path = LAYOUT.root.join(format!("{}.upload", Uuid::new_v4()));
+        let file = fs::File::create(&path).await?;
+        Ok(FileWriter {
+            name,
+            path,
+            file,
+            hasher: Sha256::new(),
+            head: Vec::with_capacity(HEAD_SIZE),
+            len: 0,
+        })
+    }
+
+    /// Finishes writing the file, stripping the EXIF data of JPEGs so that the stored hash is the
+    /// hash of the bytes which get served, JPEGs which can't be stripped are rejected
+    pub async fn finish(mut self) -> io::Result<StreamedFile> {
+        self.file.flush().await?;
+        let hash = format!("{:x}", std::mem::take(&mut self.hasher).finalize());
+        let mut file = StreamedFile {
+            content_type: tree_magic::from_u8(&self.head),
+            upload_hash: hash.clone(),
+            hash,
+            name: std::mem::take(&mut self.name),
+            path: std::mem::take(&mut self.path),
+            len: self.len,
+        };
+        if file.content_type == "image/jpeg" {
+            // the file is moved onto the blocking thread so that it still gets removed after
+            // being stripped if the upload is cut off, by a timeout for example, in the meantime,
+            // a JPEG whose EXIF data can't be stripped is dropped and removed rather than stored
+            // with it
+            let request_id = logging::current_request_id();
+            file = task::spawn_blocking(move || {
+                logging::with_request_id(request_id, || {
+                    let (hash, len) = strip_exif_file(&file.path).map_err(|err| {
+                        log::error!("Failed to strip the EXIF data of a JPEG: {}", err);
+                        err
+                    })?;
+                    file.hash = hash;
+                    file.len = len;
+                    Ok::<_, io::Error>(file)
+                })
+            })
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+        }
+        Ok(file)
+    }
+}
+
+impl Drop for FileWriter {
+    /// Removes the file if it was never finished, for example because the upload was cut off
+    fn drop(&mut self) {
+        if !self.path.as_os_str().is_empty() {
+            remove_upload(std::mem::take(&mut self.path));
+        }
+    }
+}
+
+impl AsyncWrite for FileWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = match Pin::new(&mut this.file).poll_write(cx, buf) {
+            Poll::Ready(Ok(written)) => written,
+            other => return other,
+        };
+        let data = &buf[..written];
+        this.hasher.update(data);
+        let head_left = HEAD_SIZE.saturating_sub(this.head.len());
+        this.head
+            .extend_from_slice(&data[..head_left.min(data.len())]);
+        this.len += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+/// A file which was streamed into the storage directory and hashed, but not stored in a bucket
+/// yet, it gets removed if it's dropped before being stored
+#[derive(Debug)]
+pub struct StreamedFile {
+    name: String,
+    path: PathBuf,
+    len: u64,
+    hash: String,
+    upload_hash: String,
+    content_type: String,
+}
+
+impl StreamedFile {
+    /// The name the file is stored with, cut off at the maximum length
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The hex encoded SHA-256 hash of the file as it gets stored
+    pub fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    /// The hex encoded SHA-256 hash of the file as it was uploaded, before its EXIF data was
+    /// stripped, which is what the checksums clients send describe
+    pub fn upload_hash(&self) -> &str {
+        &self.upload_hash
+    }
+
+    /// Moves the file to a path under the storage directory, which replaces whatever was there
+    /// at once since uploads are written on the same filesystem
+    pub async fn persist_to(self, path: &Path) -> io::Result<()> {
+        fs::rename(&self.path, path).await
+    }
+}
+
+impl Drop for StreamedFile {
+    fn drop(&mut self) {
+        remove_upload(std::mem::take(&mut self.path));
+    }
+}
+
+fn remove_upload_blocking(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            log::warn!("Failed to remove upload {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// Removes a leftover upload, `Drop` can't be async so the removal is moved onto a blocking
+/// thread whenever there's a runtime to keep it off of the async workers
+fn remove_upload(path: PathBuf) {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) => {
+            let request_id = logging::current_request_id();
+            handle.spawn_blocking(move || {
+                logging::with_request_id(request_id, || remove_upload_blocking(&path))
+            });
+        }
+        Err(_) => remove_upload_blocking(&path),
+    }
+}
+
+/// A writer which hashes and counts everything written to it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn invalid_jpeg(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+/// Copies a JPEG without its APP1 segments, which hold its EXIF and XMP data and often include
+/// locations, leaving the image data itself untouched
+fn strip_exif(mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+    let mut marker = [0; 2];
+    reader.read_exact(&mut marker)?;
+    if marker != [0xFF, 0xD8] {
+        return Err(invalid_jpeg("Missing start of image"));
+    }
+    writer.write_all(&marker)?;
+    loop {
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Err(invalid_jpeg("Expected a marker"));
+        }
+        // markers can be padded with any amount of fill bytes
+        while marker[1] == 0xFF {
+            reader.read_exact(&mut marker[1..])?;
+        }
+        match marker[1] {
+            // the entropy coded image data starts, the rest of the file is copied as is
+            0xDA => {
+                writer.write_all(&marker)?;
+                io::copy(&mut reader, &mut writer)?;
+                return Ok(());
+            }
+            // end of image
+            0xD9 => {
+                writer.write_all(&marker)?;
+                return Ok(());
+            }
+            // markers without a segment
+            0x01 | 0xD0..=0xD7 => writer.write_all(&marker)?,
+            kind => {
+                let mut length = [0; 2];
+                reader.read_exact(&mut length)?;
+                let length = u16::from_be_bytes(length) as u64;
+                if length < 2 {
+                    return Err(invalid_jpeg("Invalid segment length"));
+                }
+                let mut segment = reader.by_ref().take(length - 2);
+                let copied = if kind == 0xE1 {
+                    io::copy(&mut segment, &mut io::sink())?
+                } else {
+                    writer.write_all(&marker)?;
+                    writer.write_all(&(length as u16).to_be_bytes())?;
+                    io::copy(&mut segment, &mut writer)?
+                };
+                if copied != length - 2 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+    }
+}
+
+/// Strips the EXIF data of a JPEG in place, returning the hash and length of the stripped file
+fn strip_exif_file(path: &Path) -> io::Result<(String, u64)> {
+    let stripped = path.with_extension("stripped");
+    let result = (|| {
+        let mut writer = HashingWriter {
+            inner: BufWriter::new(std::fs::File::create(&stripped)?),
+            hasher: Sha256::new(),
+            len: 0,
+        };
+        strip_exif(BufReader::new(std::fs::File::open(path)?), &mut writer)?;
+        writer.flush()?;
+        std::fs::rename(&stripped, path)?;
+        Ok((format!("{:x}", writer.hasher.finalize()), writer.len))
+    })();
+    if result.is_err() {
+        std::fs::remove_file(&stripped).ok();
+    }
+    result
+}
+
+#[rocket::async_trait]
+impl<'r> FromFormField<'r> for StreamedFile {
+    async fn from_data(field: DataField<'r, '_>) -> form::Result<'r, Self> {
+        let limit = field.request.limits().get("file").unwrap_or(Limits::FILE);
+        let name = field
+            .file_name
+            .map(|n| n.dangerous_unsafe_unsanitized_raw().as_str());
+        let mut writer = FileWriter::create(name).await?;
+        let written = field.data.open(limit).stream_to(&mut writer).await?;
+        if !written.complete {
+            return Err(form::Error::from(ErrorKind::InvalidLength {
+                min: None,
+                max: Some(limit.as_u64()),
+            })
+            .into());
+        }
+        Ok(writer.finish().await?)
+    }
+}
+
+fn storage_error(bucket: &str, err: impl std::fmt::Display) -> EffisError {
+    log::error!("Failed to store file in {}: {}", bucket, err);
+    EffisError::Server("Failed to upload file".to_string())
+}
+
+/// Gets the dimensions of a stored image or video
+async fn dimensions(path: PathBuf, content_type: &str) -> (Option<u32>, Option<u32>) {
+    let result = match content_type {
+        "image/gif" | "image/jpeg" | "image/png" | "image/webp" => {
+            task::spawn_blocking(move || {
+                imagesize::size(&path)
+                    .map(|s| (Some(s.width as u32), Some(s.height as u32)))
+                    .map_err(|e| e.to_string())
+            })
+            .await
+        }
+        "video/mp4" | "video/webm" | "video/quicktime" => {
+            task::spawn_blocking(move || {
+                let probe = ffprobe::ffprobe(&path).map_err(|e| e.to_string())?;
+                let stream = probe
+                    .streams
+                    .iter()
+                    .find(|s| s.width.is_some() && s.height.is_some());
+                Ok(stream
+                    .map(|s| (s.width.map(|w| w as u32), s.height.map(|h| h as u32)))
+                    .unwrap_or((None, None)))
+            })
+            .await
+        }
+        _ => return (None, None),
+    };
+    match result {
+        Ok(Ok(dimensions)) => dimensions,
+        Ok(Err(err)) => {
+            log::warn!(
+                "Failed to get the dimensions of a {}: {}",
+                content_type,
+                err
+            );
+            (None, None)
+        }
+        Err(err) => {
+            log::warn!(
+                "Failed to get the dimensions of a {}: {}",
+                content_type,
+                err
+            );
+            (None, None)
+        }
+    }
+}
+
+/// Stores a streamed file in a bucket, reusing the blob of an identical file if there is one
+///
+/// Files are created now unless they're imported with their original timestamp
+pub async fn store(
+    file: StreamedFile,
+    bucket: &str,
+    spoiler: bool,
+    created_at: Option<u64>,
+    gen: &Mutex<IDGenerator>,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<HashedFileData, EffisError> {
+    let id = gen.lock().await.generate_id();
+    let mut tx = db.begin().await.map_err(|e| storage_error(bucket, e))?;
+    let existing = trace
+        .instrument(
+            "StoredBlob::find",
+            StoredBlob::find(&file.hash, bucket, &mut tx),
+        )
+        .await
+        .map_err(|e| storage_error(bucket, e))?;
+
+    let new_file = match existing {
+        Some(blob) => {
+            let new_file = NewFile {
+                id,
+                file_id: blob.file_id,
+                name: file.name.clone(),
+                content_type: blob.content_type,
+                hash: file.hash.clone(),
+                bucket: bucket.to_string(),
+                spoiler,
+                width: blob.width,
+                height: blob.height,
+                created_at,
+            };
+            // the blob can't be removed until this commits since its row is locked by the find
+            trace
+                .instrument("NewFile::insert", new_file.insert(&mut tx))
+                .await
+                .map_err(|e| storage_error(bucket, e))?;
+            tx.commit().await.map_err(|e| storage_error(bucket, e))?;
+            new_file
+        }
+        None => {
+            tx.rollback().await.map_err(|e| storage_error(bucket, e))?;
+            let file_id = id.to_string();
+            let path = blob_path(bucket, &file_id);
+            trace
+                .instrument("fs::rename", rename_into(&file.path, &path))
+                .await
+                .map_err(|e| storage_error(bucket, e))?;
+            let content_type = file.content_type.clone();
+            let (width, height) = trace
+                .instrument("dimensions", dimensions(path.clone(), &content_type))
+                .await;
+            let new_file = NewFile {
+                id,
+                file_id,
+                name: file.name.clone(),
+                content_type,
+                hash: file.hash.clone(),
+                bucket: bucket.to_string(),
+                spoiler,
+                width,
+                height,
+                created_at,
+            };
+            if let Err(err) = trace
+                .instrument("NewFile::insert", new_file.insert(db))
+                .await
+            {
+                fs::remove_file(&path).await.ok();
+                return Err(storage_error(bucket, err));
+            }
+            new_file
+        }
+    };
+
+    log::info!("Stored file {} in {}", id, bucket);
+    Ok(new_file.file_data())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time::Duration};
+
+    use tokio::{fs, io::AsyncWriteExt, time};
+
+    use super::{
+        blob_path, rename_into, strip_exif, with_blob, FileWriter, LAYOUT, MAX_NAME_LENGTH,
+        MAX_SHARD_DEPTH,
+    };
+
+    /// A segment with a marker and a payload
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    #[test]
+    fn strips_app1_segments() {
+        let jfif = segment(0xE0, b"JFIF\0\x01\x01");
+        let exif = segment(0xE1, b"Exif\0\0GPS goes here");
+        let tables = segment(0xDB, &[0; 65]);
+        let scan = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9];
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(&jfif);
+        jpeg.extend(&exif);
+        jpeg.extend(&tables);
+        jpeg.extend(&scan);
+        let mut stripped = vec![];
+        strip_exif(jpeg.as_slice(), &mut stripped).unwrap();
+
+        let mut expected = vec![0xFF, 0xD8];
+        expected.extend(&jfif);
+        expected.extend(&tables);
+        expected.extend(&scan);
+        assert_eq!(stripped, expected);
+    }
+
+    #[test]
+    fn leaves_jpegs_without_exif_alone() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(segment(0xE0, b"JFIF\0"));
+        jpeg.extend([0xFF, 0xFF, 0xD0, 0xFF, 0xDA, 0x01, 0x02]);
+        let mut stripped = vec![];
+        strip_exif(jpeg.as_slice(), &mut stripped).unwrap();
+        // fill bytes are dropped, everything else is kept
+        let mut expected = vec![0xFF, 0xD8];
+        expected.extend(segment(0xE0, b"JFIF\0"));
+        expected.extend([0xFF, 0xD0, 0xFF, 0xDA, 0x01, 0x02]);
+        assert_eq!(stripped, expected);
+    }
+
+    #[test]
+    fn rejects_invalid_jpegs() {
+        assert!(strip_exif(&b"GIF89a"[..], &mut vec![]).is_err());
+        let mut truncated = vec![0xFF, 0xD8];
+        truncated.extend(&segment(0xE1, b"Exif")[..5]);
+        assert!(strip_exif(truncated.as_slice(), &mut vec![]).is_err());
+    }
+
+    #[rocket::async_test]
+    async fn rejects_jpegs_which_cant_be_stripped() {
+        fs::create_dir_all(&LAYOUT.root).await.unwrap();
+        let mut writer = FileWriter::create(Some("broken.jpg")).await.unwrap();
+        let path = writer.path.clone();
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(&segment(0xE1, b"Exif\0\0GPS goes here")[..8]);
+        writer.write_all(&jpeg).await.unwrap();
+        assert!(writer.finish().await.is_err());
+        // the upload is removed on a blocking thread
+        for _ in 0..100 {
+            if !path.exists() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the unstripped upload was kept");
+    }
+
+    #[rocket::async_test]
+    async fn finds_blobs_in_other_layouts() {
+        let bucket = "layout-test";
+        let depth = (LAYOUT.shard_depth + 1) % (MAX_SHARD_DEPTH + 1);
+        let legacy = LAYOUT.blob_path_at(bucket, "legacy", depth);
+        fs::create_dir_all(legacy.parent().unwrap()).await.unwrap();
+        fs::write(&legacy, b"legacy").await.unwrap();
+        let upload = LAYOUT.root.join("layout-test.upload");
+        fs::write(&upload, b"current").await.unwrap();
+        rename_into(&upload, &blob_path(bucket, "current"))
+            .await
+            .unwrap();
+
+        let legacy = with_blob(bucket, "legacy", fs::read).await.unwrap();
+        assert_eq!(legacy, b"legacy");
+        let current = with_blob(bucket, "current", fs::read).await.unwrap();
+        assert_eq!(current, b"current");
+        let missing = with_blob(bucket, "missing", fs::read).await.unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+
+        fs::remove_dir_all(LAYOUT.root.join(bucket)).await.unwrap();
+    }
+
+    #[rocket::async_test]
+    async fn truncates_names() {
+        fs::create_dir_all(&LAYOUT.root).await.unwrap();
+        let name = "a".repeat(MAX_NAME_LENGTH * 2);
+        let file = FileWriter::create(Some(&name))
+            .await
+            .unwrap()
+            .finish()
+            .await
+            .unwrap();
+        assert_eq!(file.name(), &name[..MAX_NAME_LENGTH]);
+        let file = FileWriter::create(Some(""))
+            .await
+            .unwrap()
+            .finish()
+            .await
+            .unwrap();
+        assert_eq!(file.name(), "attachment");
+    }
+}
@@ -0,0 +1,367 @@
+use std::{collections::HashMap, convert::Infallible, env, future::Future};
+
+use anyhow::Context as _;
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    runtime,
+    sdk::{
+        export::trace::SpanExporter,
+        propagation::TraceContextPropagator,
+        trace::{self as sdktrace, TracerProvider},
+        Resource,
+    },
+    trace::{FutureExt, SpanKind, Status as SpanStatus, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Header, HeaderMap},
+    request::{FromRequest, Outcome},
+    Data, Request, Response,
+};
+
+const TRACER_NAME: &str = "effis";
+
+/// Sets up W3C trace context propagation and, if `OTEL_EXPORTER_OTLP_ENDPOINT` is set, exports
+/// spans to that OTLP collector
+pub fn init() -> Result<(), anyhow::Error> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    if let Ok(endpoint) = env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        let exporter = SpanExporterBuilder::from(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(&endpoint),
+        )
+        .build_span_exporter()
+        .with_context(|| format!("Failed to set up OTLP exporter for {}", endpoint))?;
+        install(exporter);
+        log::info!("Exporting traces to {}", endpoint);
+    }
+
+    Ok(())
+}
+
+/// Installs a global tracer provider exporting spans with the provided exporter, which can be an
+/// in-memory exporter when testing
+pub fn install<E: SpanExporter + 'static>(exporter: E) {
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_config(
+            sdktrace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                TRACER_NAME,
+            )])),
+        )
+        .build();
+    global::set_tracer_provider(provider);
+}
+
+/// Flushes all pending spans
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap<'a>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        // The trace context propagator only ever looks up its own headers
+        vec![]
+    }
+}
+
+/// The trace context of the current request, carrying its server span
+#[derive(Debug, Clone)]
+pub struct TraceContext(pub Context);
+
+impl TraceContext {
+    fn from_request(request: &Request<'_>) -> TraceContext {
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(request.headers()))
+        });
+        let tracer = global::tracer(TRACER_NAME);
+        let span = tracer
+            .span_builder(format!("{} {}", request.method(), request.uri().path()))
+            .with_kind(SpanKind::Server)
+            .with_attributes(vec![
+                KeyValue::new("http.method", request.method().as_str()),
+                KeyValue::new("http.target", request.uri().to_string()),
+            ])
+            .start_with_context(&tracer, &parent);
+        TraceContext(parent.with_span(span))
+    }
+
+    /// Starts a trace for work done outside of requests, like the subcommands
+    pub fn start(name: &'static str) -> TraceContext {
+        let span = global::tracer(TRACER_NAME).start(name);
+        TraceContext(Context::current_with_span(span))
+    }
+
+    /// Starts a child span, whose context gets passed to work which instruments its own steps
+    /// so that their spans nest under it
+    pub fn child(&self, name: &'static str) -> TraceContext {
+        let span = global::tracer(TRACER_NAME).start_with_context(name, &self.0);
+        TraceContext(self.0.with_span(span))
+    }
+
+    /// Runs a future inside of the span of this context, ending it once the future is done
+    pub async fn run<F: Future>(&self, future: F) -> F::Output {
+        let output = future.with_context(self.0.clone()).await;
+        self.0.span().end();
+        output
+    }
+
+    /// Runs a future inside of a child span, for steps which don't instrument anything
+    /// themselves
+    pub async fn instrument<F: Future>(&self, name: &'static str, future: F) -> F::Output {
+        self.child(name).run(future).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TraceContext {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(
+            request
+                .local_cache(|| TraceContext::from_request(request))
+                .clone(),
+        )
+    }
+}
+
+pub struct Telemetry;
+
+#[rocket::async_trait]
+impl Fairing for Telemetry {
+    fn info(&self) -> Info {
+        Info {
+            name: "Trace requests",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let cx = TraceContext::from_request(request);
+        request.local_cache(|| cx);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let cx = request.local_cache(|| TraceContext::from_request(request));
+        let span = cx.0.span();
+        let status = response.status();
+
+        if let Some(route) = request.route() {
+            span.update_name(format!("{} {}", request.method(), route.uri.as_str()));
+            span.set_attribute(KeyValue::new("http.route", route.uri.as_str().to_string()));
+        }
+        span.set_attribute(KeyValue::new("http.status_code", status.code as i64));
+        if status.code >= 500 {
+            span.set_status(SpanStatus::error(status.reason_lossy()));
+        }
+
+        let mut headers: HashMap<String, String> = HashMap::new();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&cx.0, &mut headers)
+        });
+        for (name, value) in headers {
+            response.set_header(Header::new(name, value));
+        }
+
+        span.end();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+    };
+
+    use lazy_static::lazy_static;
+    use opentelemetry::{
+        global,
+        sdk::{
+            export::trace::{ExportResult, SpanData, SpanExporter},
+            propagation::TraceContextPropagator,
+            trace::TracerProvider,
+        },
+        trace::{noop::NoopTracerProvider, SpanKind, TraceContextExt, TraceId},
+    };
+    use rocket::{
+        http::{ContentType, Header, Status},
+        local::asynchronous::Client,
+    };
+
+    use super::TraceContext;
+
+    lazy_static! {
+        /// Held by tests which install a global tracer provider
+        static ref TRACING: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    /// Keeps exported spans in memory so that they can be inspected
+    #[derive(Debug, Clone, Default)]
+    struct MemoryExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for MemoryExporter {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// A tracer provider exporting to memory, which is installed globally until it's dropped
+    struct MemoryTracing {
+        exporter: MemoryExporter,
+        provider: TracerProvider,
+        _lock: tokio::sync::MutexGuard<'static, ()>,
+    }
+
+    impl MemoryTracing {
+        async fn install() -> MemoryTracing {
+            let lock = TRACING.lock().await;
+            let exporter = MemoryExporter::default();
+            let provider = TracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build();
+            global::set_tracer_provider(provider.clone());
+            MemoryTracing {
+                exporter,
+                provider,
+                _lock: lock,
+            }
+        }
+
+        /// The spans of a trace which have ended
+        fn spans(&self, trace_id: TraceId) -> Vec<SpanData> {
+            self.provider.force_flush();
+            self.exporter
+                .0
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|s| s.span_context.trace_id() == trace_id)
+                .cloned()
+                .collect()
+        }
+    }
+
+    impl Drop for MemoryTracing {
+        fn drop(&mut self) {
+            global::set_tracer_provider(NoopTracerProvider::new());
+        }
+    }
+
+    fn find<'a>(spans: &'a [SpanData], name: &str) -> &'a SpanData {
+        spans
+            .iter()
+            .find(|s| s.name == name)
+            .unwrap_or_else(|| panic!("no {} span was recorded", name))
+    }
+
+    #[rocket::async_test]
+    async fn nests_spans() {
+        let tracing = MemoryTracing::install().await;
+        let root = TraceContext::start("root");
+        root.instrument("process_ratelimit", async {}).await;
+        let store = root.child("storage::store");
+        store
+            .run(async {
+                store.instrument("NewFile::insert", async {}).await;
+                store.instrument("fs::rename", async {}).await;
+            })
+            .await;
+        root.0.span().end();
+
+        let spans = tracing.spans(root.0.span().span_context().trace_id());
+        let root = find(&spans, "root");
+        let store = find(&spans, "storage::store");
+        assert_eq!(
+            find(&spans, "process_ratelimit").parent_span_id,
+            root.span_context.span_id()
+        );
+        assert_eq!(store.parent_span_id, root.span_context.span_id());
+        for name in ["NewFile::insert", "fs::rename"] {
+            assert_eq!(
+                find(&spans, name).parent_span_id,
+                store.span_context.span_id()
+            );
+        }
+    }
+
+    #[rocket::async_test]
+    #[ignore = "needs MySQL and Redis"]
+    async fn traces_requests() {
+        let tracing = MemoryTracing::install().await;
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        crate::setup().await.unwrap();
+        let client = Client::tracked(crate::rocket().unwrap()).await.unwrap();
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        // the contents are unique so that the upload isn't deduplicated and gets stored
+        let body = format!(
+            "--BOUNDARY\r\n\
+             Content-Disposition: form-data; name=\"file\"; filename=\"trace.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\n\
+             {}\r\n\
+             --BOUNDARY\r\n\
+             Content-Disposition: form-data; name=\"spoiler\"\r\n\r\n\
+             false\r\n\
+             --BOUNDARY--\r\n",
+            uuid::Uuid::new_v4()
+        );
+        let response = client
+            .post("/attachments")
+            .header(
+                ContentType::new("multipart", "form-data").with_params(("boundary", "BOUNDARY")),
+            )
+            .header(Header::new(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            ))
+            .body(body)
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::Ok);
+        let traceparent = response.headers().get_one("traceparent").unwrap();
+        assert!(traceparent.starts_with(&format!("00-{}-", trace_id)));
+
+        let trace_id = TraceId::from_hex(trace_id).unwrap();
+        let spans = tracing.spans(trace_id);
+        let server = spans
+            .iter()
+            .find(|s| s.span_kind == SpanKind::Server)
+            .expect("the request wasn't traced");
+        assert_eq!(server.name, "POST /<bucket>");
+        assert_eq!(
+            traceparent,
+            format!("00-{}-{}-01", trace_id, server.span_context.span_id())
+        );
+        assert_eq!(
+            find(&spans, "process_ratelimit").parent_span_id,
+            server.span_context.span_id()
+        );
+        let store = find(&spans, "storage::store");
+        assert_eq!(store.parent_span_id, server.span_context.span_id());
+        for name in ["StoredBlob::find", "NewFile::insert", "fs::rename"] {
+            assert_eq!(
+                find(&spans, name).parent_span_id,
+                store.span_context.span_id()
+            );
+        }
+    }
+}
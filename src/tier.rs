@@ -0,0 +1,357 @@
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Debug, Display},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    Request,
+};
+use sha2::{Digest, Sha256};
+
+use crate::client_ip::{Cidr, ClientIP};
+
+/// The name of the tier of clients which aren't in any other tier
+pub const DEFAULT_TIER: &str = "default";
+
+/// How the limits of a tier relate to the configured ratelimits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TierLimits {
+    /// Bypass ratelimits entirely
+    Exempt,
+    /// Multiply every limit by a factor
+    Multiplied(f64),
+}
+
+impl FromStr for TierLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "exempt" {
+            return Ok(TierLimits::Exempt);
+        }
+        s.parse::<f64>()
+            .ok()
+            .filter(|m| m.is_finite() && *m > 0.0)
+            .map(TierLimits::Multiplied)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown tier limits \"{}\", expected \"exempt\" or a positive multiplier",
+                    s
+                )
+            })
+    }
+}
+
+/// A ratelimit tier which clients get put in based on their IP or API key
+#[derive(Debug, Clone)]
+pub struct Tier {
+    pub name: String,
+    pub limits: TierLimits,
+}
+
+impl Tier {
+    /// Applies the tier to a limit
+    pub fn apply(&self, limit: u64) -> u64 {
+        match self.limits {
+            TierLimits::Exempt => limit,
+            TierLimits::Multiplied(multiplier) => (limit as f64 * multiplier).ceil() as u64,
+        }
+    }
+
+    pub fn is_exempt(&self) -> bool {
+        self.limits == TierLimits::Exempt
+    }
+}
+
+/// The ratelimit tiers, configured with the `EFFIS_RATELIMIT_TIERS`, `EFFIS_RATELIMIT_TIER_IPS`
+/// and `EFFIS_RATELIMIT_TIER_KEYS` environment variables
+///
+/// Each of them is a comma separated list of `key=value` pairs, tiers map their name to either
+/// `exempt` or a multiplier like `internal=exempt,bots=4`, while IP ranges and API keys map to
+/// the name of a tier like `10.0.0.0/8=internal` and `<key>=bots`. API keys are sent in the
+/// `X-Api-Key` header
+pub struct TierConf {
+    tiers: HashMap<String, Tier>,
+    ips: Vec<(Cidr, String)>,
+    keys: HashMap<String, String>,
+}
+
+impl Debug for TierConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // API keys are secrets, only the tiers they're in get printed
+        f.debug_struct("TierConf")
+            .field("tiers", &self.tiers)
+            .field("ips", &self.ips)
+            .field("keys", &self.keys.values().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Parses a comma separated list of `key=value` pairs
+fn pairs(value: &str) -> Result<Vec<(String, String)>, String> {
+    value
+        .split(',')
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .map(|pair| match pair.rsplit_once('=') {
+            Some((key, value)) => Ok((key.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("Expected a \"key=value\" pair, got \"{}\"", pair)),
+        })
+        .collect()
+}
+
+impl TierConf {
+    pub fn from_env() -> Result<TierConf, anyhow::Error> {
+        TierConf::parse(
+            &env::var("EFFIS_RATELIMIT_TIERS").unwrap_or_default(),
+            &env::var("EFFIS_RATELIMIT_TIER_IPS").unwrap_or_default(),
+            &env::var("EFFIS_RATELIMIT_TIER_KEYS").unwrap_or_default(),
+        )
+    }
+
+    /// Parses the values of the tier environment variables
+    fn parse(tiers_var: &str, ips_var: &str, keys_var: &str) -> Result<TierConf, anyhow::Error> {
+        let mut tiers = HashMap::new();
+        tiers.insert(
+            DEFAULT_TIER.to_string(),
+            Tier {
+                name: DEFAULT_TIER.to_string(),
+                limits: TierLimits::Multiplied(1.0),
+            },
+        );
+        for (name, limits) in pairs(tiers_var)
+            .and_then(|pairs| {
+                pairs
+                    .into_iter()
+                    .map(|(name, limits)| Ok((name, limits.parse::<TierLimits>()?)))
+                    .collect::<Result<Vec<_>, String>>()
+            })
+            .map_err(anyhow::Error::msg)
+            .context("Invalid \"EFFIS_RATELIMIT_TIERS\" environment variable")?
+        {
+            tiers.insert(name.clone(), Tier { name, limits });
+        }
+
+        let known_tier = |tier: &str| {
+            if tiers.contains_key(tier) {
+                Ok(())
+            } else {
+                Err(format!("Unknown tier \"{}\"", tier))
+            }
+        };
+        let ips = pairs(ips_var)
+            .and_then(|pairs| {
+                pairs
+                    .into_iter()
+                    .map(|(cidr, tier)| {
+                        known_tier(&tier)?;
+                        Ok((cidr.parse::<Cidr>()?, tier))
+                    })
+                    .collect::<Result<Vec<_>, String>>()
+            })
+            .map_err(anyhow::Error::msg)
+            .context("Invalid \"EFFIS_RATELIMIT_TIER_IPS\" environment variable")?;
+        let keys = pairs(keys_var)
+            .and_then(|pairs| {
+                pairs
+                    .into_iter()
+                    .map(|(key, tier)| {
+                        known_tier(&tier)?;
+                        Ok((key, tier))
+                    })
+                    .collect::<Result<HashMap<_, _>, String>>()
+            })
+            .map_err(anyhow::Error::msg)
+            .context("Invalid \"EFFIS_RATELIMIT_TIER_KEYS\" environment variable")?;
+
+        Ok(TierConf { tiers, ips, keys })
+    }
+
+    fn tier(&self, name: &str) -> Tier {
+        self.tiers
+            .get(name)
+            .or_else(|| self.tiers.get(DEFAULT_TIER))
+            .cloned()
+            .expect("the default tier always exists")
+    }
+}
+
+/// Who a request is ratelimited as, either the API key it was sent with or the client's IP,
+/// along with the tier they're in
+#[derive(Debug, Clone)]
+pub struct Identifier {
+    key: String,
+    pub tier: Tier,
+}
+
+impl Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.key)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Identifier {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let conf = match request.rocket().state::<TierConf>() {
+            Some(conf) => conf,
+            None => {
+                log::error!("The tier configuration isn't managed");
+                return Outcome::Failure((Status::InternalServerError, ()));
+            }
+        };
+        if let Some(key) = request.headers().get_one("X-Api-Key") {
+            return match conf.keys.get(key) {
+                Some(tier) => {
+                    // The key itself never ends up in the cache
+                    let hash = format!("{:x}", Sha256::digest(key.as_bytes()));
+                    Outcome::Success(Identifier {
+                        key: format!("key-{}", &hash[..16]),
+                        tier: conf.tier(tier),
+                    })
+                }
+                None => Outcome::Failure((Status::Unauthorized, ())),
+            };
+        }
+        let ip = try_outcome!(request.guard::<ClientIP>().await);
+        let tier = conf
+            .ips
+            .iter()
+            .find(|(cidr, _)| cidr.contains(ip.ip()))
+            .map(|(_, tier)| tier.as_str())
+            .unwrap_or(DEFAULT_TIER);
+        Outcome::Success(Identifier {
+            key: ip.to_string(),
+            tier: conf.tier(tier),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{
+        http::{Header, Status},
+        local::blocking::Client,
+    };
+
+    use super::*;
+    use crate::client_ip::ProxyConf;
+
+    #[get("/")]
+    fn identify(identifier: Identifier) -> String {
+        format!("{} {}", identifier, identifier.tier.name)
+    }
+
+    fn client(conf: TierConf) -> Client {
+        let rocket = rocket::build()
+            .manage(conf)
+            .manage(ProxyConf::from_env().unwrap())
+            .mount("/", routes![identify]);
+        Client::untracked(rocket).unwrap()
+    }
+
+    #[test]
+    fn parses_tiers() {
+        let conf = TierConf::parse(
+            " internal=exempt, bots=4,slow=0.5 ",
+            "10.0.0.0/8=internal,2001:db8::/32=bots",
+            "secret=bots",
+        )
+        .unwrap();
+        assert!(conf.tier("internal").is_exempt());
+        assert_eq!(conf.tier("bots").limits, TierLimits::Multiplied(4.0));
+        assert_eq!(conf.tier("slow").limits, TierLimits::Multiplied(0.5));
+        assert_eq!(conf.tier("made-up").name, DEFAULT_TIER);
+        assert_eq!(conf.ips.len(), 2);
+        assert_eq!(conf.keys.get("secret").unwrap(), "bots");
+
+        let conf = TierConf::parse("", "", "").unwrap();
+        assert_eq!(conf.tier(DEFAULT_TIER).limits, TierLimits::Multiplied(1.0));
+    }
+
+    #[test]
+    fn rejects_invalid_tiers() {
+        for (tiers, ips, keys) in [
+            ("bots", "", ""),
+            ("bots=0", "", ""),
+            ("bots=-2", "", ""),
+            ("bots=inf", "", ""),
+            ("bots=fast", "", ""),
+            ("bots=4", "10.0.0.0/33=bots", ""),
+            ("bots=4", "10.0.0.0/8", ""),
+            ("bots=4", "10.0.0.0/8=internal", ""),
+            ("bots=4", "", "secret=internal"),
+            ("bots=4", "", "secret"),
+        ] {
+            assert!(
+                TierConf::parse(tiers, ips, keys).is_err(),
+                "{:?}",
+                (tiers, ips, keys)
+            );
+        }
+    }
+
+    #[test]
+    fn applies_tiers() {
+        let tier = |limits| Tier {
+            name: "tier".to_string(),
+            limits,
+        };
+        assert_eq!(tier(TierLimits::Exempt).apply(10), 10);
+        assert_eq!(tier(TierLimits::Multiplied(4.0)).apply(10), 40);
+        assert_eq!(tier(TierLimits::Multiplied(0.25)).apply(10), 3);
+        assert_eq!(tier(TierLimits::Multiplied(0.01)).apply(1), 1);
+    }
+
+    #[test]
+    fn identifies_api_keys() {
+        let client = client(TierConf::parse("bots=4", "", "secret=bots").unwrap());
+        let response = client
+            .get("/")
+            .header(Header::new("X-Api-Key", "secret"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let hash = format!("{:x}", Sha256::digest(b"secret"));
+        assert_eq!(
+            response.into_string().unwrap(),
+            format!("key-{} bots", &hash[..16])
+        );
+
+        let response = client
+            .get("/")
+            .header(Header::new("X-Api-Key", "wrong"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn identifies_ips() {
+        // the first matching range wins when they overlap
+        let client = client(
+            TierConf::parse(
+                "internal=exempt,bots=4",
+                "203.0.113.0/28=internal,203.0.113.0/24=bots",
+                "",
+            )
+            .unwrap(),
+        );
+        let identify = |peer: &str| {
+            client
+                .get("/")
+                .remote(peer.parse().unwrap())
+                .dispatch()
+                .into_string()
+                .unwrap()
+        };
+        assert_eq!(identify("203.0.113.1:1234"), "203.0.113.1 internal");
+        assert_eq!(identify("203.0.113.100:1234"), "203.0.113.100 bots");
+        assert_eq!(identify("198.51.100.1:1234"), "198.51.100.1 default");
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::BTreeMap;
+
+use multer::{Constraints, Multipart, SizeLimit};
+use rocket::{
+    data::{self, Data, FromData, Limits},
+    http::Status,
+    Request,
+};
+use sqlx::{pool::PoolConnection, MySql};
+use todel::ids::IDGenerator;
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex};
+
+use crate::{
+    checksum,
+    error::EffisError,
+    models::{HashedFileData, StoredFile},
+    storage::{self, FileWriter, StreamedFile},
+    telemetry::TraceContext,
+};
+
+/// The maximum amount of files which can be uploaded in a single request
+pub const MAX_UPLOAD_FILES: usize = 10;
+/// The name of the limit on the size of multi-file forms, which is only raised for them rather
+/// than for every form through `data-form`
+pub const MULTI_FORM_LIMIT: &str = "multi-form";
+
+/// A file uploaded in a form, streamed into storage as it's received
+#[derive(Debug, FromForm)]
+pub struct FileUpload {
+    pub file: StreamedFile,
+    pub spoiler: bool,
+    /// The SHA-256 checksum the file is expected to have, as hex or base64
+    pub checksum: Option<String>,
+}
+
+/// Several files uploaded in a single form, each with its own spoiler flag
+///
+/// The form is parsed by hand with its own limit, its fields are named like the ones of a
+/// [`FileUpload`] prefixed with their index, such as `files[0].file` or `files.0.spoiler`
+#[derive(Debug)]
+pub struct MultiFileUpload {
+    pub files: Vec<FileUpload>,
+}
+
+/// The fields of a file in a multi-file form which have been received so far
+#[derive(Default)]
+struct PartialUpload {
+    file: Option<StreamedFile>,
+    spoiler: bool,
+    checksum: Option<String>,
+}
+
+/// Gets the index and key of a field of a multi-file form from its name
+fn field_key(name: &str) -> Option<(usize, &str)> {
+    let mut keys = name
+        .split(|c| c == '.' || c == '[' || c == ']')
+        .filter(|k| !k.is_empty());
+    match (keys.next(), keys.next(), keys.next(), keys.next()) {
+        (Some("files"), Some(index), Some(key), None) => Some((index.parse().ok()?, key)),
+        _ => None,
+    }
+}
+
+/// Parses a boolean form value the same way Rocket does
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "on" | "yes" | "true" | "1" => Some(true),
+        "off" | "no" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn form_error(err: multer::Error) -> (Status, EffisError) {
+    let status = match err {
+        multer::Error::StreamSizeExceeded { .. } | multer::Error::FieldSizeExceeded { .. } => {
+            Status::PayloadTooLarge
+        }
+        _ => Status::BadRequest,
+    };
+    (
+        status,
+        EffisError::Validation {
+            field_name: "files".to_string(),
+            error: err.to_string(),
+        },
+    )
+}
+
+fn write_error(err: std::io::Error) -> (Status, EffisError) {
+    log::error!("Failed to write uploaded file: {}", err);
+    (
+        Status::InternalServerError,
+        EffisError::Server("Failed to upload file".to_string()),
+    )
+}
+
+impl MultiFileUpload {
+    /// The combined size of every file in the upload
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|f| f.file.len()).sum()
+    }
+
+    async fn parse(
+        req: &Request<'_>,
+        data: Data<'_>,
+    ) -> Result<MultiFileUpload, (Status, EffisError)> {
+        let boundary = req
+            .headers()
+            .get_one("Content-Type")
+            .and_then(|c| multer::parse_boundary(c).ok())
+            .ok_or_else(|| {
+                (
+                    Status::UnsupportedMediaType,
+                    EffisError::Validation {
+                        field_name: "files".to_string(),
+                        error: "Expected a multipart form".to_string(),
+                    },
+                )
+            })?;
+        let limit = req
+            .limits()
+            .get(MULTI_FORM_LIMIT)
+            .unwrap_or(Limits::DATA_FORM);
+        let file_limit = req.limits().get("file").unwrap_or(Limits::FILE).as_u64();
+        // the stream is read one byte past the limit so that multer notices it got exceeded
+        let mut form = Multipart::with_reader_with_constraints(
+            data.open(limit + 1),
+            boundary,
+            Constraints::new().size_limit(SizeLimit::new().whole_stream(limit.as_u64())),
+        );
+
+        let mut uploads: BTreeMap<usize, PartialUpload> = BTreeMap::new();
+        while let Some(mut field) = form.next_field().await.map_err(form_error)? {
+            let (index, key) = match field.name().and_then(field_key) {
+                Some((index, key)) => (index, key.to_string()),
+                None => continue,
+            };
+            if index >= MAX_UPLOAD_FILES {
+                return Err((
+                    Status::UnprocessableEntity,
+                    EffisError::Validation {
+                        field_name: "files".to_string(),
+                        error: format!("You can upload at most {} files", MAX_UPLOAD_FILES),
+                    },
+                ));
+            }
+            let upload = uploads.entry(index).or_default();
+            match key.as_str() {
+                "file" => {
+                    let mut writer = FileWriter::create(field.file_name())
+                        .await
+                        .map_err(write_error)?;
+                    let mut len = 0;
+                    while let Some(chunk) = field.chunk().await.map_err(form_error)? {
+                        len += chunk.len() as u64;
+                        if len > file_limit {
+                            return Err((
+                                Status::PayloadTooLarge,
+                                EffisError::Validation {
+                                    field_name: "files".to_string(),
+                                    error: format!("Files can be at most {} bytes", file_limit),
+                                },
+                            ));
+                        }
+                        writer.write_all(&chunk).await.map_err(write_error)?;
+                    }
+                    upload.file = Some(writer.finish().await.map_err(write_error)?);
+                }
+                "spoiler" => {
+                    let value = field.text().await.map_err(form_error)?;
+                    upload.spoiler = parse_bool(&value).ok_or_else(|| {
+                        (
+                            Status::UnprocessableEntity,
+                            EffisError::Validation {
+                                field_name: "spoiler".to_string(),
+                                error: format!("Invalid boolean {:?}", value),
+                            },
+                        )
+                    })?;
+                }
+                "checksum" => upload.checksum = Some(field.text().await.map_err(form_error)?),
+                _ => {}
+            }
+        }
+
+        let files = uploads
+            .into_values()
+            .map(|upload| {
+                Some(FileUpload {
+                    file: upload.file?,
+                    spoiler: upload.spoiler,
+                    checksum: upload.checksum,
+                })
+            })
+            .collect::<Option<Vec<FileUpload>>>()
+            .ok_or_else(|| {
+                (
+                    Status::UnprocessableEntity,
+                    EffisError::Validation {
+                        field_name: "files".to_string(),
+                        error: "Every upload needs a file".to_string(),
+                    },
+                )
+            })?;
+        Ok(MultiFileUpload { files })
+    }
+
+    pub fn validate(&self) -> Result<(), EffisError> {
+        if self.files.is_empty() || self.files.len() > MAX_UPLOAD_FILES {
+            return Err(EffisError::Validation {
+                field_name: "files".to_string(),
+                error: format!("You must upload between 1 and {} files", MAX_UPLOAD_FILES),
+            });
+        }
+        if self.files.iter().any(|f| f.file.is_empty()) {
+            return Err(EffisError::Validation {
+                field_name: "files".to_string(),
+                error: "You cannot upload empty files".to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for MultiFileUpload {
+    type Error = EffisError;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> data::Outcome<'r, Self> {
+        match MultiFileUpload::parse(req, data).await {
+            Ok(upload) => data::Outcome::Success(upload),
+            Err((status, err)) => {
+                log::debug!("Invalid multi-file upload: {:?}", err);
+                data::Outcome::Failure((status, err))
+            }
+        }
+    }
+}
+
+/// Stores a file in a bucket, making sure it matches the checksum its client sent if there is one
+pub async fn create_file(
+    file: StreamedFile,
+    bucket: &str,
+    spoiler: bool,
+    checksum: Option<&str>,
+    gen: &Mutex<IDGenerator>,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<HashedFileData, EffisError> {
+    if let Some(checksum) = checksum {
+        checksum::verify(file.upload_hash(), checksum)?;
+    }
+    let store = trace.child("storage::store");
+    store
+        .run(storage::store(file, bucket, spoiler, None, gen, db, &store))
+        .await
+}
+
+/// Removes a file which was just created, along with its blob unless other files share it
+pub async fn remove_file(
+    id: u128,
+    bucket: &str,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<(), EffisError> {
+    let file_id = trace
+        .instrument("StoredFile::delete", StoredFile::delete(id, bucket, db))
+        .await
+        .map_err(|e| {
+            log::error!("Failed to remove file {} from {}: {}", id, bucket, e);
+            EffisError::Server("Failed to remove file".to_string())
+        })?;
+    if let Some(file_id) = file_id {
+        trace
+            .instrument(
+                "fs::remove_file",
+                storage::with_blob(bucket, &file_id, fs::remove_file),
+            )
+            .await
+            .map_err(|e| {
+                log::error!("Failed to remove blob {} from {}: {}", file_id, bucket, e);
+                EffisError::Server("Failed to remove file".to_string())
+            })?;
+    }
+    Ok(())
+}
+
+/// Stores several files in a bucket, removing every stored file again if any of them fails
+///
+/// Files which can't be removed again are reported in the error instead of the failed upload
+pub async fn create_files(
+    upload: MultiFileUpload,
+    bucket: &str,
+    gen: &Mutex<IDGenerator>,
+    db: &mut PoolConnection<MySql>,
+    trace: &TraceContext,
+) -> Result<Vec<HashedFileData>, EffisError> {
+    let mut files = Vec::with_capacity(upload.files.len());
+    for upload in upload.files {
+        let create = trace.child("upload::create_file");
+        match create
+            .run(create_file(
+                upload.file,
+                bucket,
+                upload.spoiler,
+                upload.checksum.as_deref(),
+                gen,
+                db,
+                &create,
+            ))
+            .await
+        {
+            Ok(file) => files.push(file),
+            Err(err) => {
+                log::warn!(
+                    "Failed to upload file to {}, rolling back {} files",
+                    bucket,
+                    files.len()
+                );
+                let mut leftover = vec![];
+                for file in files {
+                    let remove = trace.child("upload::remove_file");
+                    if let Err(err) = remove
+                        .run(remove_file(file.data.id, bucket, db, &remove))
+                        .await
+                    {
+                        log::error!("Failed to roll back file {}: {:?}", file.data.id, err);
+                        leftover.push(file.data.id.to_string());
+                    }
+                }
+                // the client has to know about files which are left over since the upload
+                // is otherwise expected to have no effect
+                if !leftover.is_empty() {
+                    return Err(EffisError::Server(format!(
+                        "Failed to upload files and to remove the already uploaded files {}",
+                        leftover.join(", ")
+                    )));
+                }
+                return Err(err);
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_field_names() {
+        assert_eq!(field_key("files[0].file"), Some((0, "file")));
+        assert_eq!(field_key("files.3.spoiler"), Some((3, "spoiler")));
+        assert_eq!(field_key("files[2][checksum]"), Some((2, "checksum")));
+        assert_eq!(field_key("files.file"), None);
+        assert_eq!(field_key("files[-1].file"), None);
+        assert_eq!(field_key("other[0].file"), None);
+        assert_eq!(field_key("files[0].file.name"), None);
+    }
+
+    #[test]
+    fn parses_booleans() {
+        assert_eq!(parse_bool("true"), Some(true));
+        assert_eq!(parse_bool("On"), Some(true));
+        assert_eq!(parse_bool("no"), Some(false));
+        assert_eq!(parse_bool("0"), Some(false));
+        assert_eq!(parse_bool("maybe"), None);
+    }
+}